@@ -0,0 +1,357 @@
+//! Change capture using sqlite's [session extension]
+//!
+//! A rowid watermark can tell you which rows are new, but not which rows
+//! have been updated or deleted.  This module uses the session extension to
+//! diff a table against an earlier export, and writes the result as a
+//! "delta" file.  Later, [`compact()`] folds the snapshot and its deltas
+//! back into a single snapshot.
+//!
+//! ```rust
+//! # let dir = tempfile::tempdir().unwrap();
+//! # let conn = rusqlite::Connection::open_in_memory().unwrap();
+//! # conn.execute("CREATE TABLE my_table (id INTEGER PRIMARY KEY, x TEXT)", []).unwrap();
+//! # conn.execute("INSERT INTO my_table (x) VALUES ('foo'), ('bar')", []).unwrap();
+//! let cols = sqlite2parquet::infer_schema(&conn, "my_table")
+//!     .unwrap()
+//!     .collect::<anyhow::Result<Vec<_>>>()
+//!     .unwrap();
+//! let snapshot = dir.path().join("my_table.parquet");
+//! let out = std::fs::File::create(&snapshot).unwrap();
+//! sqlite2parquet::write_table(&conn, "my_table", &cols, out, 1_000_000).unwrap();
+//!
+//! conn.execute("UPDATE my_table SET x = 'baz' WHERE x = 'bar'", []).unwrap();
+//! let delta = dir.path().join("my_table.delta-0001.parquet");
+//! let out = std::fs::File::create(&delta).unwrap();
+//! let deltas: &[&std::path::Path] = &[];
+//! sqlite2parquet::cdc::write_delta(&conn, "my_table", &snapshot, deltas, out, 1_000_000)
+//!     .unwrap()
+//!     .expect("the table has changed");
+//!
+//! let out = std::fs::File::create(dir.path().join("my_table.new.parquet")).unwrap();
+//! sqlite2parquet::cdc::compact(&snapshot, &[&delta], out, 1_000_000).unwrap();
+//! ```
+//!
+//! [session extension]: https://www.sqlite.org/sessionintro.html
+
+use crate::read::ParquetFile;
+use crate::{
+    quote, write_table_with_options, Column, LogicalType, PhysicalType, Result, WriteOptions,
+};
+use anyhow::{anyhow, bail};
+use fallible_streaming_iterator::FallibleStreamingIterator;
+use rusqlite::hooks::Action;
+use rusqlite::session::Session;
+use rusqlite::types::Value;
+use rusqlite::{Connection, DatabaseName};
+use std::io::Write;
+use std::path::Path;
+
+/// The name of the column in a delta file which says what happened to the
+/// row: "insert", "update", or "delete"
+pub const OP_COLUMN: &str = "_op";
+
+/// The metadata key under which delta files record the primary key, as a
+/// comma-separated list of column indices
+const PK_KEY: &str = "sqlite2parquet.primary_key";
+
+/// The name we give to the attached DB which holds the baseline
+const BASELINE: &str = "sqlite2parquet_baseline";
+
+/// Writes a delta file containing the changes made to `table` since it was
+/// last exported.
+///
+/// `snapshot` is a file previously created by writing the output of
+/// [`infer_schema()`](crate::infer_schema()) with [`write_table()`](crate::write_table()),
+/// and `deltas` are the delta files which have been written since then,
+/// oldest first.  They're loaded into an in-memory DB, which is then diffed
+/// against the live table.
+///
+/// The delta file has an [`OP_COLUMN`], followed by the columns of the
+/// snapshot.  For inserts and updates the row contains the new contents of
+/// the whole row; for deletes only the primary key is filled in.
+///
+/// The table must have a primary key.  If nothing has changed, `out` is left
+/// untouched and `None` is returned.
+pub fn write_delta<P: AsRef<Path>>(
+    conn: &Connection,
+    table: &str,
+    snapshot: &Path,
+    deltas: &[P],
+    out: impl Write + Send,
+    group_size: usize,
+) -> Result<Option<parquet::format::FileMetaData>> {
+    let (names, pk) = table_info(conn, table)?;
+    if pk.is_empty() {
+        bail!("{table} has no primary key, so its changes can't be tracked");
+    }
+    let snapshot = ParquetFile::open(snapshot)?;
+    if !snapshot.cols.iter().map(|x| &x.name).eq(&names) {
+        bail!("The columns of {table} don't match the snapshot");
+    }
+    conn.execute(&format!("ATTACH DATABASE ':memory:' AS {BASELINE}"), [])?;
+    let ret = write_delta_inner(conn, table, &names, &pk, snapshot, deltas, out, group_size);
+    conn.execute(&format!("DETACH DATABASE {BASELINE}"), [])?;
+    ret
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_delta_inner<P: AsRef<Path>>(
+    conn: &Connection,
+    table: &str,
+    names: &[String],
+    pk: &[usize],
+    snapshot: ParquetFile,
+    deltas: &[P],
+    out: impl Write + Send,
+    group_size: usize,
+) -> Result<Option<parquet::format::FileMetaData>> {
+    let snapshot_cols = snapshot.cols.clone();
+    load(conn, BASELINE, table, names, pk, snapshot, deltas)?;
+
+    let mut session = Session::new(conn)?;
+    session.attach(Some(table))?;
+    session.diff(DatabaseName::Attached(BASELINE), table)?;
+    if session.is_empty() {
+        return Ok(None);
+    }
+    let changeset = session.changeset()?;
+
+    // The changeset only tells us the primary key of each changed row, so we
+    // stage those in the baseline DB and join them against the live table.
+    let changes = format!("{BASELINE}.{}", quote(&format!("{table}_changes")));
+    let pk_names = pk.iter().map(|&i| quote(&names[i])).collect::<Vec<_>>();
+    conn.execute(
+        &format!("CREATE TABLE {changes} (op, {})", pk_names.join(", ")),
+        [],
+    )?;
+    let mut insert = conn.prepare(&format!(
+        "INSERT INTO {changes} VALUES (?{})",
+        ", ?".repeat(pk.len())
+    ))?;
+    let mut iter = changeset.iter()?;
+    while let Some(item) = iter.next()? {
+        let op = item.op()?.code();
+        let mut row = vec![Value::Text(op_name(op)?.to_string())];
+        for &i in pk {
+            row.push(Value::from(match op {
+                Action::SQLITE_INSERT => item.new_value(i)?,
+                _ => item.old_value(i)?,
+            }));
+        }
+        insert.execute(rusqlite::params_from_iter(row))?;
+    }
+
+    let join = pk_names
+        .iter()
+        .map(|x| format!("t.{x} IS d.{x}"))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    let mut cols = vec![Column {
+        name: OP_COLUMN.to_string(),
+        required: true,
+        physical_type: PhysicalType::ByteArray,
+        logical_type: Some(LogicalType::String),
+        encoding: None,
+        dictionary: true,
+        query: format!("SELECT op FROM {changes} ORDER BY rowid"),
+        list: false,
+        map: false,
+        values: vec![],
+    }];
+    for (i, col) in snapshot_cols.into_iter().enumerate() {
+        let name = quote(&col.name);
+        let (required, query) = if pk.contains(&i) {
+            (
+                col.required,
+                format!("SELECT {name} FROM {changes} ORDER BY rowid"),
+            )
+        } else {
+            // Deleted rows don't exist in the live table, so these will be
+            // null
+            (
+                false,
+                format!(
+                    "SELECT t.{name} FROM {changes} AS d \
+                    LEFT JOIN main.{} AS t ON {join} ORDER BY d.rowid",
+                    quote(table),
+                ),
+            )
+        };
+        cols.push(Column {
+            required,
+            query,
+            ..col
+        });
+    }
+    let pk_meta = pk.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+    let kv = parquet::format::KeyValue::new(PK_KEY.to_string(), pk_meta.join(","));
+    let opts = WriteOptions {
+        key_value_metadata: vec![kv],
+        ..Default::default()
+    };
+    let metadata =
+        write_table_with_options(conn, table, &cols, &opts, out, group_size, |_| Ok(()))?;
+    Ok(Some(metadata))
+}
+
+/// Merges a snapshot with the deltas which were written after it, producing
+/// a new snapshot.
+///
+/// This doesn't need access to the original database.  Rows keep their
+/// position from the snapshot, and newly inserted rows are appended to the
+/// end.
+pub fn compact<P: AsRef<Path>>(
+    snapshot: &Path,
+    deltas: &[P],
+    out: impl Write + Send,
+    group_size: usize,
+) -> Result<parquet::format::FileMetaData> {
+    let snapshot = ParquetFile::open(snapshot)?;
+    let table = snapshot.name.clone();
+    let mut cols = snapshot.cols.clone();
+    let names = cols.iter().map(|x| x.name.clone()).collect::<Vec<_>>();
+    let pk = match deltas.first() {
+        Some(path) => delta_pk(&ParquetFile::open(path.as_ref())?)?,
+        None => vec![],
+    };
+
+    let conn = Connection::open_in_memory()?;
+    load(&conn, "main", &table, &names, &pk, snapshot, deltas)?;
+    for col in &mut cols {
+        let name = quote(&col.name);
+        // An insert may have introduced nulls into a previously-full column
+        if col.required {
+            col.required = conn.query_row(
+                &format!(
+                    "SELECT COUNT(*) == 0 FROM {} WHERE {name} IS NULL",
+                    quote(&table)
+                ),
+                [],
+                |x| x.get(0),
+            )?;
+        }
+        col.query = format!("SELECT {name} FROM {} ORDER BY rowid", quote(&table));
+    }
+    write_table_with_options(
+        &conn,
+        &table,
+        &cols,
+        &WriteOptions::default(),
+        out,
+        group_size,
+        |_| Ok(()),
+    )
+}
+
+/// Creates `schema.table` and fills it with the contents of the snapshot,
+/// with the deltas applied
+fn load<P: AsRef<Path>>(
+    conn: &Connection,
+    schema: &str,
+    table: &str,
+    names: &[String],
+    pk: &[usize],
+    snapshot: ParquetFile,
+    deltas: &[P],
+) -> Result<()> {
+    let target = format!("{schema}.{}", quote(table));
+    let mut defs = names.iter().map(|x| quote(x)).collect::<Vec<_>>();
+    if !pk.is_empty() {
+        let pk_names = pk.iter().map(|&i| quote(&names[i])).collect::<Vec<_>>();
+        defs.push(format!("PRIMARY KEY ({})", pk_names.join(", ")));
+    }
+    conn.execute(&format!("CREATE TABLE {target} ({})", defs.join(", ")), [])?;
+
+    let values = format!("VALUES (?{})", ", ?".repeat(names.len() - 1));
+    let mut insert = conn.prepare(&format!("INSERT INTO {target} {values}"))?;
+    for row in snapshot.rows() {
+        insert.execute(rusqlite::params_from_iter(row?))?;
+    }
+
+    if deltas.is_empty() {
+        return Ok(());
+    }
+    let pk_names = pk.iter().map(|&i| quote(&names[i])).collect::<Vec<_>>();
+    let updates = (0..names.len())
+        .filter(|i| !pk.contains(i))
+        .map(|i| format!("{0} = excluded.{0}", quote(&names[i])))
+        .collect::<Vec<_>>();
+    let mut upsert = conn.prepare(&format!(
+        "INSERT INTO {target} {values} ON CONFLICT ({}) DO {}",
+        pk_names.join(", "),
+        if updates.is_empty() {
+            "NOTHING".to_string()
+        } else {
+            format!("UPDATE SET {}", updates.join(", "))
+        },
+    ))?;
+    let mut delete = conn.prepare(&format!(
+        "DELETE FROM {target} WHERE {}",
+        pk_names
+            .iter()
+            .enumerate()
+            .map(|(i, x)| format!("{x} IS ?{}", i + 1))
+            .collect::<Vec<_>>()
+            .join(" AND "),
+    ))?;
+    for path in deltas {
+        let path = path.as_ref();
+        let delta = ParquetFile::open(path)?;
+        if delta_pk(&delta)? != pk
+            || delta.cols.first().map(|x| x.name.as_str()) != Some(OP_COLUMN)
+            || !delta.cols[1..].iter().map(|x| &x.name).eq(names)
+        {
+            bail!("{}: Delta doesn't match the snapshot", path.display());
+        }
+        for row in delta.rows() {
+            let mut row = row?;
+            let op = row.remove(0);
+            match op {
+                Value::Text(x) if x == "delete" => {
+                    delete.execute(rusqlite::params_from_iter(pk.iter().map(|&i| &row[i])))?;
+                }
+                Value::Text(x) if x == "insert" || x == "update" => {
+                    upsert.execute(rusqlite::params_from_iter(row))?;
+                }
+                x => bail!("{}: Unknown operation: {x:?}", path.display()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The names of the table's columns, and the indices of its primary key
+/// columns
+fn table_info(conn: &Connection, table: &str) -> Result<(Vec<String>, Vec<usize>)> {
+    let mut stmt = conn.prepare("SELECT name, pk FROM pragma_table_info(?1) ORDER BY cid")?;
+    let infos = stmt
+        .query_map([table], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut pk = infos
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, pk))| *pk > 0)
+        .map(|(i, (_, pk))| (*pk, i))
+        .collect::<Vec<_>>();
+    pk.sort();
+    let names = infos.into_iter().map(|(name, _)| name).collect();
+    Ok((names, pk.into_iter().map(|(_, i)| i).collect()))
+}
+
+fn delta_pk(delta: &ParquetFile) -> Result<Vec<usize>> {
+    let pk = delta
+        .key_value(PK_KEY)
+        .ok_or_else(|| anyhow!("{} isn't a delta file", delta.name))?;
+    Ok(pk.split(',').map(|x| x.parse()).collect::<Result<_, _>>()?)
+}
+
+fn op_name(op: Action) -> Result<&'static str> {
+    match op {
+        Action::SQLITE_INSERT => Ok("insert"),
+        Action::SQLITE_UPDATE => Ok("update"),
+        Action::SQLITE_DELETE => Ok("delete"),
+        x => Err(anyhow!("Unexpected change: {x:?}")),
+    }
+}
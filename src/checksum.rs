@@ -0,0 +1,137 @@
+use crate::read::ParquetFile;
+use crate::{quote, Result};
+use anyhow::Context;
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+use sha3::{Digest, Sha3_224};
+use std::path::Path;
+
+/// The key under which [`sha3sum_table()`] is recorded in the file metadata,
+/// when a table is exported as-is
+pub const SHA3SUM_KEY: &str = "sqlite2parquet.sha3sum";
+
+/// Computes the same digest as the `.sha3sum` command in the sqlite shell.
+///
+/// `.sha3sum` hashes the content of each table in turn, in case-insensitive
+/// order of name.  Feed the tables to this in the same order, then call
+/// [`finish()`](Sha3Sum::finish) to get the digest as a hex string.
+///
+/// Only the content is hashed, not the way it's stored, so this is a good
+/// way to check that a database survived a round-trip through parquet.
+#[derive(Default, Clone)]
+pub struct Sha3Sum(Sha3_224);
+
+impl Sha3Sum {
+    pub fn new() -> Sha3Sum {
+        Sha3Sum::default()
+    }
+
+    /// Starts a new table.  The rows must follow in rowid order.
+    pub fn table(&mut self, name: &str) {
+        // .sha3sum hashes the text of the statement it used to read the table
+        let sql = format!(
+            "SELECT * FROM {} NOT INDEXED;",
+            quote(&name.to_ascii_lowercase())
+        );
+        self.0.update(format!("S{}:", sql.len()));
+        self.0.update(sql);
+    }
+
+    pub fn row<'a>(&mut self, row: impl IntoIterator<Item = ValueRef<'a>>) {
+        self.0.update(b"R");
+        for x in row {
+            match x {
+                ValueRef::Null => self.0.update(b"N"),
+                ValueRef::Integer(x) => {
+                    self.0.update(b"I");
+                    self.0.update(x.to_be_bytes());
+                }
+                ValueRef::Real(x) => {
+                    self.0.update(b"F");
+                    self.0.update(x.to_bits().to_be_bytes());
+                }
+                ValueRef::Text(x) => {
+                    self.0.update(format!("T{}:", x.len()));
+                    self.0.update(x);
+                }
+                ValueRef::Blob(x) => {
+                    self.0.update(format!("B{}:", x.len()));
+                    self.0.update(x);
+                }
+            }
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.0
+            .finalize()
+            .iter()
+            .map(|x| format!("{x:02x}"))
+            .collect()
+    }
+}
+
+/// Hashes the content of a sqlite table.  The result is the same as
+/// `.sha3sum <table>` in the sqlite shell.
+pub fn sha3sum_table(conn: &Connection, table: &str) -> Result<String> {
+    let mut hasher = Sha3Sum::new();
+    hasher.table(table);
+    let mut stmnt = conn.prepare(&format!("SELECT * FROM {} NOT INDEXED", quote(table)))?;
+    let n_cols = stmnt.column_count();
+    let mut rows = stmnt.query([])?;
+    while let Some(row) = rows.next()? {
+        hasher.row((0..n_cols).map(|i| row.get_ref_unwrap(i)));
+    }
+    Ok(hasher.finish())
+}
+
+/// Hashes the content of a parquet file which was exported from a whole
+/// table.
+///
+/// The values are decoded back to sqlite values first, so if the export was
+/// lossless the result matches [`sha3sum_table()`] on the original table.
+///
+/// ```rust
+/// # let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// # conn.execute_batch("CREATE TABLE my_table (x INTEGER, y TEXT);
+/// #     INSERT INTO my_table VALUES (1, 'foo'), (NULL, 'bar');").unwrap();
+/// let cols = sqlite2parquet::infer_schema(&conn, "my_table")
+///     .unwrap()
+///     .collect::<anyhow::Result<Vec<_>>>()
+///     .unwrap();
+/// # let dir = tempfile::tempdir().unwrap();
+/// let path = dir.path().join("my_table.parquet");
+/// let out = std::fs::File::create(&path).unwrap();
+/// sqlite2parquet::write_table(&conn, "my_table", &cols, &out, 1000).unwrap();
+/// assert_eq!(
+///     sqlite2parquet::sha3sum_file(&path).unwrap(),
+///     sqlite2parquet::sha3sum_table(&conn, "my_table").unwrap(),
+/// );
+/// ```
+pub fn sha3sum_file(path: &Path) -> Result<String> {
+    sha3sum_files(&[path])
+}
+
+/// Hashes the content of several parquet files, each of which was exported
+/// from a whole table.
+///
+/// This is the equivalent of running `.sha3sum` on a database containing
+/// those tables.  The files can be given in any order.
+pub fn sha3sum_files<P: AsRef<Path>>(paths: &[P]) -> Result<String> {
+    let mut files = paths
+        .iter()
+        .map(|path| {
+            let path = path.as_ref();
+            ParquetFile::open(path).with_context(|| path.display().to_string())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    files.sort_by_key(|x| x.name.to_ascii_lowercase());
+    let mut hasher = Sha3Sum::new();
+    for file in files {
+        hasher.table(&file.name);
+        for row in file.rows() {
+            hasher.row(row?.iter().map(ValueRef::from));
+        }
+    }
+    Ok(hasher.finish())
+}
@@ -5,16 +5,51 @@ use rusqlite::types::ValueRef;
 /// Like rusqlite::FromSql, but we make our own because of the orphan rule
 pub trait FromSqlite: Sized {
     fn from_sqlite(x: ValueRef) -> anyhow::Result<Self>;
+
+    /// Like [`from_sqlite()`](Self::from_sqlite), for fixed-length columns
+    /// whose values must be `len` bytes long
+    fn from_sqlite_fixed(x: ValueRef, _len: usize) -> anyhow::Result<Self> {
+        Self::from_sqlite(x)
+    }
+
+    /// Like [`from_sqlite()`](Self::from_sqlite), and also says whether the
+    /// conversion lost information, for types which are narrower than
+    /// sqlite's
+    fn from_sqlite_lossy(x: ValueRef) -> anyhow::Result<(Self, bool)> {
+        Ok((Self::from_sqlite(x)?, false))
+    }
 }
 impl FromSqlite for bool {
     fn from_sqlite(x: ValueRef) -> anyhow::Result<Self> {
         match x {
-            ValueRef::Integer(x) => Ok(x == 1),
+            ValueRef::Integer(0) => Ok(false),
+            ValueRef::Integer(1) => Ok(true),
             ValueRef::Null => unreachable!("Nulls are handled separately"),
-            _ => Err(anyhow!("Can't convert {x:?} to a bool!")),
+            _ => Err(anyhow!(
+                "Can't convert {x:?} to a bool (only 0 and 1 are allowed, \
+                unless booleans are parsed leniently)"
+            )),
         }
     }
 }
+
+/// The spellings of true and false understood by lenient boolean parsing,
+/// in lower case
+pub(crate) const TRUE_TEXTS: &[&str] = &["1", "true", "t", "yes", "y", "on"];
+pub(crate) const FALSE_TEXTS: &[&str] = &["0", "false", "f", "no", "n", "off"];
+
+/// Parses text the way lenient boolean parsing would.  Case and surrounding
+/// whitespace are ignored.
+pub(crate) fn parse_bool(x: &str) -> Option<bool> {
+    let x = x.trim().to_lowercase();
+    if TRUE_TEXTS.contains(&x.as_str()) {
+        Some(true)
+    } else if FALSE_TEXTS.contains(&x.as_str()) {
+        Some(false)
+    } else {
+        None
+    }
+}
 impl FromSqlite for i32 {
     fn from_sqlite(x: ValueRef) -> anyhow::Result<Self> {
         match x {
@@ -44,22 +79,67 @@ impl FromSqlite for Int96 {
 }
 impl FromSqlite for f32 {
     fn from_sqlite(x: ValueRef) -> anyhow::Result<Self> {
-        match x {
-            ValueRef::Real(x) => Ok(x as f32),
-            ValueRef::Null => unreachable!("Nulls are handled separately"),
-            _ => Err(anyhow!("Can't convert {x:?} to a f32!")),
-        }
+        Ok(f64::from_sqlite(x)? as f32)
+    }
+
+    fn from_sqlite_lossy(x: ValueRef) -> anyhow::Result<(Self, bool)> {
+        let x = f64::from_sqlite(x)?;
+        let y = x as f32;
+        Ok((y, f64::from(y) != x && !x.is_nan()))
     }
 }
+/// Numbers are converted the way sqlite's REAL affinity would: integers
+/// become floats (sqlite stores whole numbers in REAL columns as integers),
+/// and so does text which looks like a number
 impl FromSqlite for f64 {
     fn from_sqlite(x: ValueRef) -> anyhow::Result<Self> {
         match x {
             ValueRef::Real(x) => Ok(x),
+            ValueRef::Integer(x) => Ok(x as f64),
+            ValueRef::Text(text) => std::str::from_utf8(text)
+                .ok()
+                .and_then(parse_real)
+                .ok_or_else(|| anyhow!("Can't convert {x:?} to a f64!")),
             ValueRef::Null => unreachable!("Nulls are handled separately"),
-            _ => Err(anyhow!("Can't convert {x:?} to a f64!")),
+            ValueRef::Blob(_) => Err(anyhow!("Can't convert {x:?} to a f64!")),
         }
     }
 }
+
+/// Parses text which sqlite would convert to a number when storing it in a
+/// REAL column: a decimal literal like `-1.5e3`, `.5`, or `5.`, with
+/// optional whitespace around it.  Unlike Rust, sqlite doesn't understand
+/// `inf` or `NaN`, and `1e999` is infinite rather than an error.
+fn parse_real(x: &str) -> Option<f64> {
+    // sqlite's idea of whitespace includes vertical tabs, unlike Rust's
+    // `trim_ascii()`
+    let x = x.trim_matches([' ', '\t', '\n', '\x0b', '\x0c', '\r']);
+    let digits = |x: &str| x.len() - x.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let rest = x.strip_prefix(['+', '-']).unwrap_or(x);
+    let n_int = digits(rest);
+    let rest = &rest[n_int..];
+    let (n_frac, rest) = match rest.strip_prefix('.') {
+        Some(rest) => (digits(rest), &rest[digits(rest)..]),
+        None => (0, rest),
+    };
+    if n_int + n_frac == 0 {
+        return None;
+    }
+    let rest = match rest.strip_prefix(['e', 'E']) {
+        Some(exp) => {
+            let exp = exp.strip_prefix(['+', '-']).unwrap_or(exp);
+            match digits(exp) {
+                0 => return None,
+                n => &exp[n..],
+            }
+        }
+        None => rest,
+    };
+    match rest.is_empty() {
+        true => x.parse().ok(),
+        false => None,
+    }
+}
 impl FromSqlite for ByteArray {
     fn from_sqlite(x: ValueRef) -> anyhow::Result<Self> {
         match x {
@@ -74,10 +154,229 @@ impl FromSqlite for ByteArray {
 impl FromSqlite for FixedLenByteArray {
     fn from_sqlite(x: ValueRef) -> anyhow::Result<Self> {
         match x {
-            ValueRef::Text(_) => todo!(),
-            ValueRef::Blob(_) => todo!(),
+            ValueRef::Text(x) | ValueRef::Blob(x) => Ok(FixedLenByteArray::from(Vec::from(x))),
             ValueRef::Null => unreachable!("Nulls are handled separately"),
             _ => Err(anyhow!("Can't convert {x:?} to a FixedLenByteArray!")),
         }
     }
+
+    /// UUIDs stored as text (eg. `'0b9e8d1c-...'`) are converted to their
+    /// 16 bytes.  Anything else must already be the right length.
+    fn from_sqlite_fixed(x: ValueRef, len: usize) -> anyhow::Result<Self> {
+        let bytes = match x {
+            ValueRef::Text(text) if len == 16 && text.len() != 16 => std::str::from_utf8(text)
+                .ok()
+                .and_then(parse_uuid)
+                .map(Vec::from),
+            _ => None,
+        };
+        let x = match bytes {
+            Some(bytes) => FixedLenByteArray::from(bytes),
+            None => FixedLenByteArray::from_sqlite(x)?,
+        };
+        if x.len() != len {
+            return Err(anyhow!(
+                "Can't write {} bytes to a column of {len}-byte values",
+                x.len()
+            ));
+        }
+        Ok(x)
+    }
+}
+
+/// Parses a UUID in its usual text form (`xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`),
+/// or as 32 hex digits without the dashes
+fn parse_uuid(x: &str) -> Option<[u8; 16]> {
+    let hex = match x.len() {
+        36 => {
+            let dashes = [8, 13, 18, 23];
+            if dashes.iter().any(|&i| x.as_bytes()[i] != b'-') {
+                return None;
+            }
+            x.replace('-', "")
+        }
+        32 => x.to_string(),
+        _ => return None,
+    };
+    if !hex.bytes().all(|x| x.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut bytes = [0; 16];
+    for (i, x) in bytes.iter_mut().enumerate() {
+        *x = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Converts an element of a JSON array to a sqlite value, the same way
+/// sqlite's `json_each()` would
+pub(crate) fn json_to_value(x: &serde_json::Value) -> rusqlite::types::Value {
+    use rusqlite::types::Value;
+    match x {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(x) => Value::Integer(i64::from(*x)),
+        serde_json::Value::Number(x) => match x.as_i64() {
+            Some(x) => Value::Integer(x),
+            None => Value::Real(x.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(x) => Value::Text(x.clone()),
+        x @ (serde_json::Value::Array(_) | serde_json::Value::Object(_)) => {
+            Value::Text(x.to_string())
+        }
+    }
+}
+
+/// Converts a list element read from a parquet file to JSON
+pub(crate) fn field_to_json(x: &parquet::record::Field) -> anyhow::Result<serde_json::Value> {
+    use serde_json::Value as Json;
+    Ok(match field_to_value(x)? {
+        rusqlite::types::Value::Null => Json::Null,
+        rusqlite::types::Value::Integer(y) => match x {
+            parquet::record::Field::Bool(x) => Json::Bool(*x),
+            _ => Json::from(y),
+        },
+        rusqlite::types::Value::Real(x) => Json::from(x),
+        rusqlite::types::Value::Text(x) => Json::String(x),
+        rusqlite::types::Value::Blob(x) => Json::String(String::from_utf8_lossy(&x).into()),
+    })
+}
+
+/// Converts a value read from a parquet file back to a sqlite value.
+///
+/// This is the inverse of [`FromSqlite`], so integer-like fields become
+/// integers, strings become text, and other byte arrays become blobs.
+/// Lists and maps become JSON arrays and objects.
+pub(crate) fn field_to_value(x: &parquet::record::Field) -> anyhow::Result<rusqlite::types::Value> {
+    use parquet::record::Field;
+    use rusqlite::types::Value;
+    Ok(match x {
+        Field::Null => Value::Null,
+        Field::Bool(x) => Value::Integer(i64::from(*x)),
+        Field::Byte(x) => Value::Integer(i64::from(*x)),
+        Field::Short(x) => Value::Integer(i64::from(*x)),
+        Field::Int(x) => Value::Integer(i64::from(*x)),
+        Field::Long(x) => Value::Integer(*x),
+        Field::UByte(x) => Value::Integer(i64::from(*x)),
+        Field::UShort(x) => Value::Integer(i64::from(*x)),
+        Field::UInt(x) => Value::Integer(i64::from(*x)),
+        Field::ULong(x) => Value::Integer(i64::try_from(*x)?),
+        Field::Float(x) => Value::Real(f64::from(*x)),
+        Field::Double(x) => Value::Real(*x),
+        Field::Str(x) => Value::Text(x.clone()),
+        Field::Bytes(x) => Value::Blob(x.data().to_vec()),
+        Field::Date(x) => Value::Integer(i64::from(*x)),
+        Field::TimestampMillis(x) => Value::Integer(*x),
+        Field::TimestampMicros(x) => Value::Integer(*x),
+        Field::ListInternal(x) => {
+            let elems = x
+                .elements()
+                .iter()
+                .map(field_to_json)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Value::Text(serde_json::Value::Array(elems).to_string())
+        }
+        Field::MapInternal(x) => {
+            let entries = x
+                .entries()
+                .iter()
+                .map(|(k, v)| {
+                    let k = match field_to_value(k)? {
+                        Value::Text(k) => k,
+                        k => return Err(anyhow!("Can't use {k:?} as a JSON key")),
+                    };
+                    Ok((k, field_to_json(v)?))
+                })
+                .collect::<anyhow::Result<serde_json::Map<_, _>>>()?;
+            Value::Text(serde_json::Value::Object(entries).to_string())
+        }
+        _ => return Err(anyhow!("Can't convert {x} to a sqlite value")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reals() {
+        let cases = [
+            ("1.5", Some(1.5)),
+            (" -2 ", Some(-2.)),
+            ("\x0b+.5\t", Some(0.5)),
+            ("5.", Some(5.)),
+            ("1e3", Some(1000.)),
+            ("-2.5E-1", Some(-0.25)),
+            ("1e999", Some(f64::INFINITY)),
+            ("", None),
+            (".", None),
+            ("e5", None),
+            ("1e", None),
+            ("1.5x", None),
+            ("0x10", None),
+            ("inf", None),
+            ("-Infinity", None),
+            ("NaN", None),
+            ("1 2", None),
+        ];
+        for (x, expected) in cases {
+            assert_eq!(parse_real(x), expected, "{x:?}");
+        }
+        let text = |x: &'static str| ValueRef::Text(x.as_bytes());
+        assert_eq!(f64::from_sqlite(ValueRef::Integer(5)).unwrap(), 5.);
+        assert!(f64::from_sqlite(text("nan")).is_err());
+
+        // Narrowing to f32 is checked as the value is converted
+        assert_eq!(f32::from_sqlite_lossy(text("0.5")).unwrap(), (0.5, false));
+        assert_eq!(f32::from_sqlite_lossy(text("0.1")).unwrap(), (0.1, true));
+        let big = ValueRef::Integer(16_777_217);
+        assert_eq!(f32::from_sqlite_lossy(big).unwrap(), (16_777_216., true));
+    }
+
+    #[test]
+    fn parse_bools() {
+        for x in ["1", "true", "T", " Yes ", "y", "ON\n"] {
+            assert_eq!(parse_bool(x), Some(true), "{x:?}");
+        }
+        for x in ["0", "FALSE", "f", "no", " N", "off"] {
+            assert_eq!(parse_bool(x), Some(false), "{x:?}");
+        }
+        for x in ["", "2", "-1", "yess", "o", "true false", "1.0"] {
+            assert_eq!(parse_bool(x), None, "{x:?}");
+        }
+        // Strict parsing only accepts 0 and 1
+        assert!(bool::from_sqlite(ValueRef::Integer(1)).unwrap());
+        assert!(!bool::from_sqlite(ValueRef::Integer(0)).unwrap());
+        assert!(bool::from_sqlite(ValueRef::Integer(2)).is_err());
+        assert!(bool::from_sqlite(ValueRef::Text(b"true")).is_err());
+    }
+
+    #[test]
+    fn fixed_len_byte_arrays() {
+        let uuid = FixedLenByteArray::from_sqlite_fixed(
+            ValueRef::Text(b"0B9E8D1C-52E1-4C1F-8A3B-9C4D5E6F7A8B"),
+            16,
+        )
+        .unwrap();
+        assert_eq!(uuid.data()[..4], [0x0b, 0x9e, 0x8d, 0x1c]);
+        let hex = FixedLenByteArray::from_sqlite_fixed(
+            ValueRef::Text(b"0b9e8d1c52e14c1f8a3b9c4d5e6f7a8b"),
+            16,
+        )
+        .unwrap();
+        assert_eq!(hex, uuid);
+        // Sixteen bytes of text are taken as they are
+        let text = FixedLenByteArray::from_sqlite_fixed(ValueRef::Text(b"0123456789abcdef"), 16);
+        assert_eq!(text.unwrap().data(), b"0123456789abcdef");
+        let blob = FixedLenByteArray::from_sqlite_fixed(ValueRef::Blob(&[1; 12]), 12).unwrap();
+        assert_eq!(blob.data(), [1; 12]);
+
+        assert!(FixedLenByteArray::from_sqlite_fixed(ValueRef::Blob(&[1; 15]), 16).is_err());
+        assert!(FixedLenByteArray::from_sqlite_fixed(ValueRef::Text(b"not-a-uuid"), 16).is_err());
+        assert!(FixedLenByteArray::from_sqlite_fixed(
+            ValueRef::Text(b"0b9e8d1c-52e1-4c1f-8a3b-9c4d5e6f7a8g"),
+            16
+        )
+        .is_err());
+        assert!(FixedLenByteArray::from_sqlite_fixed(ValueRef::Integer(1), 16).is_err());
+    }
 }
@@ -0,0 +1,258 @@
+use crate::{infer_schema_with_options, quote, Column, InferOptions, Result};
+use anyhow::bail;
+use rusqlite::Connection;
+use std::collections::BTreeMap;
+
+/// Columns to pull into a table's export from the tables related to it by
+/// foreign keys.  See [`infer_related_columns()`].
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Denormalize {
+    /// For each table which this one refers to, the columns to add (`*` for
+    /// all of them).  They're named `<table>.<column>`, so they're written
+    /// as a struct.
+    pub parents: BTreeMap<String, Vec<String>>,
+    /// Tables which refer to this one.  The rows which refer to each row of
+    /// this table are nested as a list of structs, named `<table>[]`.
+    pub children: Vec<String>,
+}
+
+impl Denormalize {
+    /// Follows all the foreign keys to and from `table`: all the columns of
+    /// the tables it refers to, and all the tables which refer to it.
+    pub fn all(conn: &Connection, table: &str) -> Result<Denormalize> {
+        let mut parents = BTreeMap::new();
+        for fk in foreign_keys(conn, table)? {
+            parents.insert(fk.table, vec!["*".to_string()]);
+        }
+        let mut children = vec![];
+        let mut stmnt = conn.prepare(
+            "SELECT name FROM sqlite_schema WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+        )?;
+        let tables = stmnt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for child in tables {
+            let fks = foreign_keys(conn, &child)?;
+            if fks.iter().any(|fk| fk.table.eq_ignore_ascii_case(table)) {
+                children.push(child);
+            }
+        }
+        Ok(Denormalize { parents, children })
+    }
+}
+
+/// Makes the extra columns for `table` described by `denorm`.
+///
+/// The types are inferred from the related tables.  The columns from
+/// parent tables are always optional, since the foreign key may be null.
+/// The child tables are queried once per row of `table` (in fact, once per
+/// column), so their foreign keys should be indexed.
+///
+/// ```rust
+/// # let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// # conn.execute_batch("CREATE TABLE customers (id INTEGER PRIMARY KEY, name TEXT);
+/// #     CREATE TABLE orders (id INTEGER PRIMARY KEY, customer_id INTEGER REFERENCES customers);
+/// #     CREATE TABLE order_items (order_id INTEGER REFERENCES orders, sku TEXT, qty INTEGER);
+/// #     INSERT INTO customers VALUES (1, 'alice');
+/// #     INSERT INTO orders VALUES (10, 1), (11, NULL);
+/// #     INSERT INTO order_items VALUES (10, 'x', 1), (10, 'y', 2);").unwrap();
+/// let denorm = sqlite2parquet::Denormalize {
+///     parents: [("customers".to_string(), vec!["name".to_string()])].into(),
+///     children: vec!["order_items".to_string()],
+/// };
+/// let mut cols = sqlite2parquet::infer_schema(&conn, "orders")
+///     .unwrap()
+///     .collect::<anyhow::Result<Vec<_>>>()
+///     .unwrap();
+/// let opts = sqlite2parquet::InferOptions::default();
+/// cols.extend(sqlite2parquet::infer_related_columns(&conn, "orders", &denorm, &opts).unwrap());
+/// let names = cols.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+/// assert_eq!(
+///     names,
+///     ["id", "customer_id", "customers.name", "order_items[].order_id", "order_items[].sku", "order_items[].qty"],
+/// );
+/// # let dir = tempfile::tempdir().unwrap();
+/// let out = std::fs::File::create(dir.path().join("orders.parquet")).unwrap();
+/// sqlite2parquet::write_table(&conn, "orders", &cols, &out, 1000).unwrap();
+/// ```
+pub fn infer_related_columns(
+    conn: &Connection,
+    table: &str,
+    denorm: &Denormalize,
+    opts: &InferOptions,
+) -> Result<Vec<Column>> {
+    let opts = InferOptions {
+        shred_json: false,
+        ..opts.clone()
+    };
+    let mut cols = vec![];
+    let fks = foreign_keys(conn, table)?;
+    for (parent, names) in &denorm.parents {
+        let fks = fks
+            .iter()
+            .filter(|fk| fk.table.eq_ignore_ascii_case(parent))
+            .collect::<Vec<_>>();
+        if fks.is_empty() {
+            bail!("{table} has no foreign key referring to {parent}");
+        }
+        let parent_cols = infer_schema_with_options(conn, parent, &opts)?
+            .filter(|col| {
+                col.as_ref()
+                    .map_or(true, |col| names.iter().any(|x| x == "*" || x == &col.name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for name in names {
+            if name != "*" && !parent_cols.iter().any(|col| &col.name == name) {
+                bail!("{parent} has no column {name}");
+            }
+        }
+        for fk in &fks {
+            // If there are several foreign keys to the same table, the
+            // groups are distinguished by the referring columns
+            let group = match fks.len() {
+                1 => parent.clone(),
+                _ => format!("{parent}_{}", fk.from.join("_")),
+            };
+            let cond = fk.condition(conn, "_r", table)?;
+            for col in &parent_cols {
+                cols.push(Column {
+                    name: format!("{group}.{}", col.name),
+                    required: false,
+                    query: format!(
+                        "SELECT (SELECT _r.{} FROM {} AS _r WHERE {cond}) FROM {} ORDER BY rowid",
+                        quote(&col.name),
+                        quote(parent),
+                        quote(table),
+                    ),
+                    ..col.clone()
+                });
+            }
+        }
+    }
+    for child in &denorm.children {
+        let fks = foreign_keys(conn, child)?
+            .into_iter()
+            .filter(|fk| fk.table.eq_ignore_ascii_case(table))
+            .collect::<Vec<_>>();
+        if fks.is_empty() {
+            bail!("{child} has no foreign key referring to {table}");
+        }
+        let child_cols =
+            infer_schema_with_options(conn, child, &opts)?.collect::<Result<Vec<_>>>()?;
+        for fk in &fks {
+            let group = match fks.len() {
+                1 => child.clone(),
+                _ => format!("{child}_{}", fk.from.join("_")),
+            };
+            let cond = fk.reverse_condition(conn, "_c", table)?;
+            for col in &child_cols {
+                cols.push(Column {
+                    name: format!("{group}[].{}", col.name),
+                    query: format!(
+                        "SELECT (SELECT json_group_array(v) FROM \
+                            (SELECT _c.{} AS v FROM {} AS _c WHERE {cond} ORDER BY _c.rowid)) \
+                        FROM {} ORDER BY rowid",
+                        quote(&col.name),
+                        quote(child),
+                        quote(table),
+                    ),
+                    ..col.clone()
+                });
+            }
+        }
+    }
+    Ok(cols)
+}
+
+/// A row of `pragma_foreign_key_list`, with the columns of composite keys
+/// gathered together
+struct ForeignKey {
+    /// The referring columns
+    from: Vec<String>,
+    /// The referenced table
+    table: String,
+    /// The referenced columns.  `None` for the primary key.
+    to: Vec<Option<String>>,
+}
+
+fn foreign_keys(conn: &Connection, table: &str) -> Result<Vec<ForeignKey>> {
+    let mut stmnt = conn.prepare(
+        "SELECT id, \"table\", \"from\", \"to\" FROM pragma_foreign_key_list(?1) ORDER BY id, seq",
+    )?;
+    let mut rows = stmnt.query([table])?;
+    let mut fks: Vec<(i64, ForeignKey)> = vec![];
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        if fks.last().map(|x| x.0) != Some(id) {
+            let fk = ForeignKey {
+                from: vec![],
+                table: row.get(1)?,
+                to: vec![],
+            };
+            fks.push((id, fk));
+        }
+        let fk = &mut fks.last_mut().unwrap().1;
+        fk.from.push(row.get(2)?);
+        fk.to
+            .push(row.get::<_, Option<String>>(3)?.filter(|x| !x.is_empty()));
+    }
+    Ok(fks.into_iter().map(|x| x.1).collect())
+}
+
+impl ForeignKey {
+    /// The referenced columns, looking up the primary key if necessary
+    fn to(&self, conn: &Connection) -> Result<Vec<String>> {
+        if self.to.iter().all(|x| x.is_some()) {
+            return Ok(self.to.iter().flatten().cloned().collect());
+        }
+        let mut stmnt =
+            conn.prepare("SELECT name FROM pragma_table_info(?1) WHERE pk > 0 ORDER BY pk")?;
+        let pk = stmnt
+            .query_map([&self.table], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        match pk.len() {
+            0 if self.from.len() == 1 => Ok(vec!["rowid".to_string()]),
+            n if n == self.from.len() => Ok(pk),
+            _ => bail!("{}: can't find the columns referred to", self.table),
+        }
+    }
+
+    /// Matches rows of the referenced table (aliased as `alias`) to rows of
+    /// the referring table
+    fn condition(&self, conn: &Connection, alias: &str, from_table: &str) -> Result<String> {
+        Ok(self
+            .to(conn)?
+            .iter()
+            .zip(&self.from)
+            .map(|(to, from)| {
+                format!(
+                    "{alias}.{} = {}.{}",
+                    quote(to),
+                    quote(from_table),
+                    quote(from)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" AND "))
+    }
+
+    /// Matches rows of the referring table (aliased as `alias`) to rows of
+    /// the referenced table
+    fn reverse_condition(&self, conn: &Connection, alias: &str, to_table: &str) -> Result<String> {
+        Ok(self
+            .to(conn)?
+            .iter()
+            .zip(&self.from)
+            .map(|(to, from)| {
+                format!(
+                    "{alias}.{} = {}.{}",
+                    quote(from),
+                    quote(to_table),
+                    quote(to)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" AND "))
+    }
+}
@@ -0,0 +1,308 @@
+use crate::read::ParquetFile;
+use crate::{Column, PhysicalType, Result};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A description of an existing parquet file, as recorded in its footer
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct FileInfo {
+    /// The name of the table, as stored in the parquet schema
+    pub name: String,
+    pub n_rows: i64,
+    pub columns: Vec<ColumnInfo>,
+    pub row_groups: Vec<RowGroupInfo>,
+    pub key_value_metadata: BTreeMap<String, Option<String>>,
+}
+
+/// A column, with its statistics summed over all row groups
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ColumnInfo {
+    /// The column definition, recovered from the schema.  The query is empty.
+    pub column: Column,
+    /// The type from the file, if `column` can only approximate it (eg.
+    /// `INT96`, or a decimal)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unsupported_type: Option<String>,
+    /// The encodings used by any of the row groups
+    pub encodings: Vec<String>,
+    #[serde(flatten)]
+    pub stats: ChunkInfo,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RowGroupInfo {
+    pub n_rows: i64,
+    pub compressed_bytes: i64,
+    pub uncompressed_bytes: i64,
+    pub columns: Vec<ChunkInfo>,
+}
+
+/// Statistics about a column within a row group (or a whole file)
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ChunkInfo {
+    pub compressed_bytes: i64,
+    pub uncompressed_bytes: i64,
+    /// `None` if the writer didn't record it
+    pub null_count: Option<i64>,
+    pub min: Option<StatValue>,
+    pub max: Option<StatValue>,
+}
+
+/// A min/max statistic, decoded according to the column's type
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(untagged)]
+pub enum StatValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    /// Hex-encoded
+    Bytes(String),
+}
+
+impl ChunkInfo {
+    /// Adds up the statistics for the same column in several row groups
+    fn sum<'a>(chunks: impl Iterator<Item = &'a ChunkInfo>) -> ChunkInfo {
+        let mut total = ChunkInfo {
+            compressed_bytes: 0,
+            uncompressed_bytes: 0,
+            null_count: Some(0),
+            min: None,
+            max: None,
+        };
+        for chunk in chunks {
+            total.compressed_bytes += chunk.compressed_bytes;
+            total.uncompressed_bytes += chunk.uncompressed_bytes;
+            total.null_count = total.null_count.zip(chunk.null_count).map(|(x, y)| x + y);
+            match (&chunk.min, &total.min) {
+                (Some(x), Some(min)) if x.cmp(min).is_ge() => (),
+                (Some(x), _) => total.min = Some(x.clone()),
+                (None, _) => (),
+            }
+            match (&chunk.max, &total.max) {
+                (Some(x), Some(max)) if x.cmp(max).is_le() => (),
+                (Some(x), _) => total.max = Some(x.clone()),
+                (None, _) => (),
+            }
+        }
+        total
+    }
+}
+
+impl StatValue {
+    /// Decodes a plain-encoded statistic
+    fn decode(col: &Column, bytes: &[u8]) -> Option<StatValue> {
+        Some(match col.physical_type {
+            PhysicalType::Boolean => StatValue::Bool(*bytes.first()? != 0),
+            PhysicalType::Int32 => {
+                StatValue::Int(i32::from_le_bytes(bytes.try_into().ok()?).into())
+            }
+            PhysicalType::Int64 => StatValue::Int(i64::from_le_bytes(bytes.try_into().ok()?)),
+            PhysicalType::Float => {
+                StatValue::Float(f32::from_le_bytes(bytes.try_into().ok()?).into())
+            }
+            PhysicalType::Double => StatValue::Float(f64::from_le_bytes(bytes.try_into().ok()?)),
+            PhysicalType::ByteArray | PhysicalType::FixedLenByteArray(_) => {
+                match std::str::from_utf8(bytes) {
+                    Ok(x)
+                        if matches!(
+                            col.logical_type,
+                            Some(crate::LogicalType::String | crate::LogicalType::Enum)
+                        ) =>
+                    {
+                        StatValue::Text(x.to_string())
+                    }
+                    _ => StatValue::Bytes(bytes.iter().map(|x| format!("{x:02x}")).collect()),
+                }
+            }
+        })
+    }
+
+    /// Values from the same column always have the same variant
+    fn cmp(&self, other: &StatValue) -> Ordering {
+        match (self, other) {
+            (StatValue::Bool(x), StatValue::Bool(y)) => x.cmp(y),
+            (StatValue::Int(x), StatValue::Int(y)) => x.cmp(y),
+            (StatValue::Float(x), StatValue::Float(y)) => x.total_cmp(y),
+            (StatValue::Text(x), StatValue::Text(y)) => x.cmp(y),
+            (StatValue::Bytes(x), StatValue::Bytes(y)) => x.cmp(y),
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+impl std::fmt::Display for StatValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StatValue::Bool(x) => write!(f, "{x}"),
+            StatValue::Int(x) => write!(f, "{x}"),
+            StatValue::Float(x) => write!(f, "{x}"),
+            StatValue::Text(x) => write!(f, "{x:?}"),
+            StatValue::Bytes(x) => write!(f, "x'{x}'"),
+        }
+    }
+}
+
+/// Reads the footer of a parquet file and describes its contents.
+///
+/// Nothing but the footer is read, so this is quick even for large files.
+/// The file doesn't need to have been written by sqlite2parquet: columns
+/// with types we don't support are described as well as we can, and their
+/// actual types are given in
+/// [`unsupported_type`](ColumnInfo::unsupported_type).
+pub fn inspect(path: &Path) -> Result<FileInfo> {
+    let (file, unsupported) = ParquetFile::open_lenient(path)?;
+    let meta = file.metadata();
+    // The leaves which make up each column: for maps, the keys come first,
+    // and the statistics are taken from the values
+    let mut leaves = vec![];
+    for col in &file.cols {
+        let start = leaves.last().map_or(0, |x: &std::ops::Range<usize>| x.end);
+        leaves.push(start..start + col.n_leaves());
+    }
+    let row_groups = meta
+        .row_groups()
+        .iter()
+        .map(|group| {
+            let thrift = group.to_thrift();
+            RowGroupInfo {
+                n_rows: group.num_rows(),
+                compressed_bytes: group.compressed_size(),
+                uncompressed_bytes: group.total_byte_size(),
+                columns: leaves
+                    .iter()
+                    .zip(&file.cols)
+                    .map(|(leaves, col)| {
+                        let chunks = &thrift.columns[leaves.clone()];
+                        let meta = chunks.last().and_then(|x| x.meta_data.as_ref());
+                        let stats = meta.and_then(|x| x.statistics.as_ref());
+                        let sum_bytes = |f: fn(&parquet::format::ColumnMetaData) -> i64| {
+                            chunks
+                                .iter()
+                                .filter_map(|x| x.meta_data.as_ref())
+                                .map(f)
+                                .sum()
+                        };
+                        // `min` and `max` are deprecated in favour of `min_value`
+                        // and `max_value`, but older writers only set those
+                        let decode = |new: &Option<Vec<u8>>, old: &Option<Vec<u8>>| {
+                            StatValue::decode(col, new.as_ref().or(old.as_ref())?)
+                        };
+                        ChunkInfo {
+                            compressed_bytes: sum_bytes(|x| x.total_compressed_size),
+                            uncompressed_bytes: sum_bytes(|x| x.total_uncompressed_size),
+                            null_count: stats.and_then(|x| x.null_count),
+                            min: stats.and_then(|x| decode(&x.min_value, &x.min)),
+                            max: stats.and_then(|x| decode(&x.max_value, &x.max)),
+                        }
+                    })
+                    .collect(),
+            }
+        })
+        .collect::<Vec<_>>();
+    let columns = file
+        .cols
+        .iter()
+        .enumerate()
+        .map(|(i, col)| {
+            let mut encodings = vec![];
+            for group in meta.row_groups() {
+                for leaf in leaves[i].clone() {
+                    for enc in group.column(leaf).encodings() {
+                        let enc = enc.to_string();
+                        if !encodings.contains(&enc) {
+                            encodings.push(enc);
+                        }
+                    }
+                }
+            }
+            ColumnInfo {
+                column: col.clone(),
+                unsupported_type: unsupported[i].clone(),
+                encodings,
+                stats: ChunkInfo::sum(row_groups.iter().map(|x| &x.columns[i])),
+            }
+        })
+        .collect();
+    let key_value_metadata = meta
+        .file_metadata()
+        .key_value_metadata()
+        .into_iter()
+        .flatten()
+        .map(|kv| (kv.key.clone(), kv.value.clone()))
+        .collect();
+    Ok(FileInfo {
+        name: file.name.clone(),
+        n_rows: meta.file_metadata().num_rows(),
+        columns,
+        row_groups,
+        key_value_metadata,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::column::writer::ColumnWriter;
+    use parquet::data_type::Int96;
+    use std::sync::Arc;
+
+    #[test]
+    fn inspect_unsupported_types() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("other.parquet");
+        let schema = parquet::schema::parser::parse_message_type(
+            "message other {
+                REQUIRED INT96 ts;
+                REQUIRED INT32 price (DECIMAL(9, 2));
+                REQUIRED BYTE_ARRAY name (STRING);
+            }",
+        )
+        .unwrap();
+        let props = parquet::file::properties::WriterProperties::builder().build();
+        let file = std::fs::File::create(&path).unwrap();
+        let mut wtr =
+            parquet::file::writer::SerializedFileWriter::new(file, Arc::new(schema), props.into())
+                .unwrap();
+        let mut group = wtr.next_row_group().unwrap();
+        while let Some(mut col) = group.next_column().unwrap() {
+            match col.untyped() {
+                ColumnWriter::Int96ColumnWriter(x) => {
+                    let mut ts = Int96::new();
+                    ts.set_data(1, 2, 3);
+                    x.write_batch(&[ts], None, None).unwrap();
+                }
+                ColumnWriter::Int32ColumnWriter(x) => {
+                    x.write_batch(&[1234], None, None).unwrap();
+                }
+                ColumnWriter::ByteArrayColumnWriter(x) => {
+                    x.write_batch(&["foo".into()], None, None).unwrap();
+                }
+                _ => unreachable!(),
+            }
+            col.close().unwrap();
+        }
+        group.close().unwrap();
+        wtr.close().unwrap();
+
+        let info = inspect(&path).unwrap();
+        assert_eq!(info.n_rows, 1);
+        let unsupported = info
+            .columns
+            .iter()
+            .map(|x| x.unsupported_type.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(unsupported[0], Some("INT96"));
+        assert!(unsupported[1].unwrap().starts_with("INT32 (Decimal"));
+        assert_eq!(unsupported[2], None);
+        assert_eq!(info.columns[1].stats.min, Some(StatValue::Int(1234)));
+        assert_eq!(
+            info.columns[2].stats.max,
+            Some(StatValue::Text("foo".into()))
+        );
+
+        // Reading the data still requires the types to be supported
+        assert!(ParquetFile::open(&path).is_err());
+    }
+}
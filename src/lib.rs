@@ -25,7 +25,8 @@ let cols = sqlite2parquet::infer_schema(&conn, "my_table")
     .unwrap()
     .collect::<anyhow::Result<Vec<_>>>()
     .unwrap();
-let out_path = std::fs::File::create("my_table.parquet").unwrap();
+# let dir = tempfile::tempdir().unwrap();
+let out_path = std::fs::File::create(dir.path().join("my_table.parquet")).unwrap();
 sqlite2parquet::write_table(&conn, "my_table", &cols, &out_path, 1_000_000).unwrap();
 ```
 
@@ -48,6 +49,9 @@ let cols = vec![
         encoding: None,
         dictionary: true,
         query: "SELECT category FROM my_table GROUP BY category ORDER BY MIN(timestamp)".to_string(),
+        list: false,
+        map: false,
+        values: vec![],
     },
     Column {
         name: "first_timestamp".to_string(),
@@ -57,47 +61,83 @@ let cols = vec![
         encoding: Some(Encoding::DeltaBinaryPacked),
         dictionary: false,
         query: "SELECT MIN(timestamp) FROM my_table GROUP BY category ORDER BY MIN(timestamp)".to_string(),
+        list: false,
+        map: false,
+        values: vec![],
     },
 ];
 
-let out_path = std::fs::File::create("category_start_times.parquet").unwrap();
+# let dir = tempfile::tempdir().unwrap();
+let out_path = std::fs::File::create(dir.path().join("category_start_times.parquet")).unwrap();
 write_table(&conn, "category_start_times", &cols, &out_path, 1_000_000).unwrap();
 ```
 
  */
 
+#[cfg(feature = "cdc")]
+pub mod cdc;
+mod checksum;
 mod conversion;
+mod denormalize;
+mod inspect;
+mod merge;
+mod read;
+mod restore;
 mod schema;
+mod verify;
 
+pub use crate::checksum::*;
 use crate::conversion::FromSqlite;
+pub use crate::denormalize::*;
+pub use crate::inspect::*;
+pub use crate::merge::*;
+pub use crate::restore::*;
 pub use crate::schema::*;
-use anyhow::{Context, Result};
+pub use crate::verify::*;
+use anyhow::{bail, Context, Result};
 use fallible_streaming_iterator::FallibleStreamingIterator;
+use parquet::column::writer::ColumnWriter;
 use parquet::file::writer::SerializedFileWriter;
+use rusqlite::types::{Value, ValueRef};
 use rusqlite::Connection;
 use std::io::Write;
 use std::sync::Arc;
 
+/// Quotes an SQL identifier
+pub(crate) fn quote(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
 fn mk_writer<W: Write + Send>(
     table_name: &str,
     cols: &[Column],
+    opts: &WriteOptions,
     out: W,
 ) -> Result<SerializedFileWriter<W>> {
-    let fields = cols
-        .iter()
-        .map(|col| Arc::new(col.as_parquet().unwrap()))
-        .collect::<Vec<_>>();
+    let fields = schema::parquet_fields(cols)?;
     let schema = parquet::schema::types::Type::group_type_builder(table_name)
         .with_fields(fields)
         .build()?;
+    let mut key_value_metadata = opts.key_value_metadata.clone();
+    for col in cols.iter().filter(|x| !x.values.is_empty()) {
+        key_value_metadata.push(parquet::format::KeyValue::new(
+            format!("{}.{}", schema::ENUM_VALUES_KEY, col.name),
+            serde_json::to_string(&col.values)?,
+        ));
+    }
     let mut bldr = parquet::file::properties::WriterProperties::builder()
-        .set_compression(parquet::basic::Compression::ZSTD(Default::default()));
+        .set_compression(parquet::basic::Compression::ZSTD(Default::default()))
+        .set_key_value_metadata((!key_value_metadata.is_empty()).then_some(key_value_metadata));
+    if let Some(created_by) = &opts.created_by {
+        bldr = bldr.set_created_by(created_by.clone());
+    }
     for col in cols {
-        let path = parquet::schema::types::ColumnPath::new(vec![col.name.clone()]);
-        if let Some(enc) = col.encoding() {
-            bldr = bldr.set_column_encoding(path.clone(), enc)
+        for path in col.paths() {
+            if let Some(enc) = col.encoding() {
+                bldr = bldr.set_column_encoding(path.clone(), enc)
+            }
+            bldr = bldr.set_column_dictionary_enabled(path, col.dictionary);
         }
-        bldr = bldr.set_column_dictionary_enabled(path, col.dictionary);
     }
     let props = bldr.build();
     Ok(SerializedFileWriter::new(
@@ -150,10 +190,65 @@ pub fn write_table_with_progress(
     cols: &[Column],
     out: impl Write + Send,
     group_size: usize,
+    progress_cb: impl FnMut(Progress) -> Result<()>,
+) -> Result<parquet::format::FileMetaData> {
+    let opts = WriteOptions::default();
+    write_table_with_options(conn, table_name, cols, &opts, out, group_size, progress_cb)
+}
+
+/// Settings for [`write_table_with_options()`] which affect the file
+/// metadata
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Extra key-value pairs to record in the file metadata
+    pub key_value_metadata: Vec<parquet::format::KeyValue>,
+    /// Replaces the `created_by` field of the file metadata, which normally
+    /// names the version of parquet-rs.  Nothing else in the file depends on
+    /// the time or the environment, so with a fixed `created_by` the same
+    /// data always gives the same bytes.
+    ///
+    /// ```rust
+    /// # let conn = rusqlite::Connection::open_in_memory().unwrap();
+    /// # conn.execute_batch("CREATE TABLE my_table (x INTEGER, y TEXT);
+    /// #     INSERT INTO my_table VALUES (1, 'foo'), (NULL, 'bar');").unwrap();
+    /// use sha3::Digest;
+    /// let opts = sqlite2parquet::WriteOptions {
+    ///     created_by: Some("sqlite2parquet".into()),
+    ///     ..Default::default()
+    /// };
+    /// let export = || {
+    ///     let cols = sqlite2parquet::infer_schema(&conn, "my_table")
+    ///         .unwrap()
+    ///         .collect::<anyhow::Result<Vec<_>>>()
+    ///         .unwrap();
+    ///     let mut out = vec![];
+    ///     sqlite2parquet::write_table_with_options(
+    ///         &conn, "my_table", &cols, &opts, &mut out, 1000, |_| Ok(()),
+    ///     )
+    ///     .unwrap();
+    ///     sha3::Sha3_256::digest(out)
+    /// };
+    /// assert_eq!(export(), export());
+    /// ```
+    pub created_by: Option<String>,
+}
+
+/// Like [`write_table_with_progress()`], but with some control over the
+/// file metadata.
+///
+/// For more information, see the docs for [`write_table()`].
+pub fn write_table_with_options(
+    conn: &Connection,
+    table_name: &str,
+    cols: &[Column],
+    opts: &WriteOptions,
+    out: impl Write + Send,
+    group_size: usize,
     mut progress_cb: impl FnMut(Progress) -> Result<()>,
 ) -> Result<parquet::format::FileMetaData> {
-    let mut wtr = mk_writer(table_name, cols, out)?;
+    let mut wtr = mk_writer(table_name, cols, opts, out)?;
 
+    let leaves = schema::leaf_columns(cols);
     let mut stmnts = cols
         .iter()
         .map(|col| conn.prepare(&col.query).unwrap())
@@ -167,10 +262,27 @@ pub fn write_table_with_progress(
     }
 
     let mut progress = Progress::default();
+    let mut entries = MapEntries::new();
     while selects[0].get().is_some() {
-        write_group(&mut wtr, &mut selects, group_size, |n_cols| {
-            progress_cb(Progress { n_cols, ..progress })
-        })
+        write_group(
+            &mut wtr,
+            |i, col_wtr| {
+                let rows = &mut selects[leaves[i]];
+                match MapLeaf::new(&leaves, i) {
+                    Some(MapLeaf::Keys) => {
+                        entries.clear();
+                        while let Some(row) = rows.get().filter(|_| entries.len() < group_size) {
+                            entries.push(decode_map(row.get_ref(0)?)?);
+                            rows.advance()?;
+                        }
+                        write_map_col(&entries, col_wtr)
+                    }
+                    Some(MapLeaf::Values) => write_map_col(&entries, col_wtr),
+                    None => write_col(rows, group_size, col_wtr),
+                }
+            },
+            |n_cols| progress_cb(Progress { n_cols, ..progress }),
+        )
         .context(format!("Group {}", progress.n_groups))?;
         progress.n_rows += group_size as u64;
         progress.n_groups += 1;
@@ -179,31 +291,93 @@ pub fn write_table_with_progress(
     Ok(metadata)
 }
 
+/// Creates a parquet file from the results of a single query.
+///
+/// Unlike [`write_table()`], all the columns come from one `SELECT`, which
+/// is only run once.  This is the way to go for joins and aggregations,
+/// which would be expensive to run once per column.  `cols` describes the
+/// columns of the result, in order; their `query` fields are ignored.
+///
+/// Each row group is buffered in memory before being written, so you may
+/// want a smaller `group_size` than you would use with [`write_table()`].
+///
+/// Aggregates built with `json_group_array()` can be written as list
+/// columns: infer the schema, then set `list` and the element type.
+///
+/// ```rust
+/// # let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// # conn.execute_batch("CREATE TABLE orders (customer TEXT NOT NULL, amount REAL);
+/// #     INSERT INTO orders VALUES ('alice', 1.5), ('bob', 2.0), ('alice', 3.0);").unwrap();
+/// let sql = "SELECT customer, json_group_array(amount) AS amounts FROM orders GROUP BY customer";
+/// let mut cols = sqlite2parquet::infer_query_schema(&conn, sql)
+///     .unwrap()
+///     .collect::<anyhow::Result<Vec<_>>>()
+///     .unwrap();
+/// cols[1].list = true;
+/// cols[1].physical_type = sqlite2parquet::PhysicalType::Double;
+/// cols[1].logical_type = None;
+/// # let dir = tempfile::tempdir().unwrap();
+/// let out = std::fs::File::create(dir.path().join("amounts_by_customer.parquet")).unwrap();
+/// let opts = sqlite2parquet::WriteOptions::default();
+/// sqlite2parquet::write_query(&conn, "amounts_by_customer", sql, &cols, &opts, &out, 1000)
+///     .unwrap();
+/// ```
+pub fn write_query(
+    conn: &Connection,
+    table_name: &str,
+    sql: &str,
+    cols: &[Column],
+    opts: &WriteOptions,
+    out: impl Write + Send,
+    group_size: usize,
+) -> Result<parquet::format::FileMetaData> {
+    let mut stmnt = conn.prepare(sql).context(sql.to_string())?;
+    if stmnt.column_count() != cols.len() {
+        bail!(
+            "The query returns {} columns, but {} were given",
+            stmnt.column_count(),
+            cols.len()
+        );
+    }
+    let mut wtr = mk_writer(table_name, cols, opts, out)?;
+    let leaves = schema::leaf_columns(cols);
+    let group_size = group_size.max(1);
+    let mut buf = Vec::with_capacity(group_size);
+    let mut n_groups = 0;
+    let mut rows = stmnt.query([])?;
+    loop {
+        let row = rows.next()?;
+        if let Some(row) = row {
+            buf.push(
+                (0..cols.len())
+                    .map(|i| Ok(Value::from(row.get_ref(i)?)))
+                    .collect::<Result<Vec<_>>>()?,
+            );
+        }
+        if buf.len() == group_size || (row.is_none() && !buf.is_empty()) {
+            write_group_from_rows(&mut wtr, &buf, &leaves).context(format!("Group {n_groups}"))?;
+            buf.clear();
+            n_groups += 1;
+        }
+        if row.is_none() {
+            break;
+        }
+    }
+    Ok(wtr.close()?)
+}
+
+/// Writes a single row group, calling `write_col` to fill in each column
 fn write_group<W: Write + Send>(
     wtr: &mut SerializedFileWriter<W>,
-    selects: &mut [rusqlite::Rows],
-    group_size: usize,
+    mut write_col: impl FnMut(usize, &mut ColumnWriter) -> Result<()>,
     mut progress_cb: impl FnMut(u64) -> Result<()>,
 ) -> Result<Arc<parquet::file::metadata::RowGroupMetaData>> {
     let mut group_wtr = wtr.next_row_group()?;
-    let mut selects_iter = selects.iter_mut();
     let mut n_cols_written = 0;
     while let Some(mut col_wtr) = group_wtr.next_column()? {
         progress_cb(n_cols_written)?;
-        let select = selects_iter.next().unwrap();
-
-        use parquet::column::writer::ColumnWriter::*;
-        let x = match col_wtr.untyped() {
-            BoolColumnWriter(wtr) => write_col(select, group_size, wtr),
-            Int32ColumnWriter(wtr) => write_col(select, group_size, wtr),
-            Int64ColumnWriter(wtr) => write_col(select, group_size, wtr),
-            Int96ColumnWriter(wtr) => write_col(select, group_size, wtr),
-            FloatColumnWriter(wtr) => write_col(select, group_size, wtr),
-            DoubleColumnWriter(wtr) => write_col(select, group_size, wtr),
-            ByteArrayColumnWriter(wtr) => write_col(select, group_size, wtr),
-            FixedLenByteArrayColumnWriter(wtr) => write_col(select, group_size, wtr),
-        };
-        x.context(format!("Column {}", n_cols_written))?;
+        write_col(n_cols_written as usize, col_wtr.untyped())
+            .context(format!("Column {}", n_cols_written))?;
         col_wtr
             .close()
             .context(format!("Column {}", n_cols_written))?;
@@ -212,7 +386,117 @@ fn write_group<W: Write + Send>(
     Ok(group_wtr.close()?)
 }
 
-fn write_col<T>(
+/// Writes a single row group from rows of values, one per column.  `leaves`
+/// is from [`schema::leaf_columns()`].
+fn write_group_from_rows<W: Write + Send>(
+    wtr: &mut SerializedFileWriter<W>,
+    rows: &[Vec<Value>],
+    leaves: &[usize],
+) -> Result<Arc<parquet::file::metadata::RowGroupMetaData>> {
+    let mut entries = MapEntries::new();
+    write_group(
+        wtr,
+        |i, x| match MapLeaf::new(leaves, i) {
+            Some(MapLeaf::Keys) => {
+                entries = rows
+                    .iter()
+                    .map(|row| decode_map(ValueRef::from(&row[leaves[i]])))
+                    .collect::<Result<_>>()?;
+                write_map_col(&entries, x)
+            }
+            Some(MapLeaf::Values) => write_map_col(&entries, x),
+            None => write_col_from_rows(rows, leaves[i], x),
+        },
+        |_| Ok(()),
+    )
+}
+
+/// The objects in a map column, one per row (`None` for nulls).  They're
+/// decoded once, while the keys are written, and the values are written
+/// from the same objects, so the two always line up.
+type MapEntries = Vec<Option<Vec<(String, serde_json::Value)>>>;
+
+/// Which half of a map column a leaf is
+enum MapLeaf {
+    Keys,
+    Values,
+}
+
+impl MapLeaf {
+    /// Map columns are the only ones with two leaves: the keys, and then
+    /// the values
+    fn new(leaves: &[usize], i: usize) -> Option<MapLeaf> {
+        if leaves.get(i + 1) == Some(&leaves[i]) {
+            Some(MapLeaf::Keys)
+        } else if i > 0 && leaves[i - 1] == leaves[i] {
+            Some(MapLeaf::Values)
+        } else {
+            None
+        }
+    }
+}
+
+/// Decodes a JSON object from a map column
+fn decode_map(x: ValueRef) -> Result<Option<Vec<(String, serde_json::Value)>>> {
+    let json = match x {
+        ValueRef::Null => return Ok(None),
+        ValueRef::Text(x) | ValueRef::Blob(x) => {
+            serde_json::from_slice(x).context("Expected JSON")?
+        }
+        _ => bail!("Expected JSON, found {x:?}"),
+    };
+    match json {
+        serde_json::Value::Object(x) => Ok(Some(x.into_iter().collect())),
+        x => bail!("Expected a JSON object, found {x}"),
+    }
+}
+
+/// Writes the keys or the values of some maps
+fn write_map_col(entries: &MapEntries, wtr: &mut ColumnWriter) -> Result<()> {
+    use ColumnWriter::*;
+    match wtr {
+        BoolColumnWriter(wtr) => write_map_col_typed(entries, wtr),
+        Int32ColumnWriter(wtr) => write_map_col_typed(entries, wtr),
+        Int64ColumnWriter(wtr) => write_map_col_typed(entries, wtr),
+        Int96ColumnWriter(wtr) => write_map_col_typed(entries, wtr),
+        FloatColumnWriter(wtr) => write_map_col_typed(entries, wtr),
+        DoubleColumnWriter(wtr) => write_map_col_typed(entries, wtr),
+        ByteArrayColumnWriter(wtr) => write_map_col_typed(entries, wtr),
+        FixedLenByteArrayColumnWriter(wtr) => write_map_col_typed(entries, wtr),
+    }
+}
+
+fn write_map_col_typed<T>(
+    entries: &MapEntries,
+    wtr: &mut parquet::column::writer::ColumnWriterImpl<T>,
+) -> Result<()>
+where
+    T: parquet::data_type::DataType,
+    T::T: FromSqlite,
+{
+    let mut batch = Batch::<T>::new(wtr);
+    for x in entries {
+        batch.push_entries(x.as_deref())?;
+    }
+    batch.write(wtr)
+}
+
+/// Writes the next `group_size` values from a query
+fn write_col(iter: &mut rusqlite::Rows, group_size: usize, wtr: &mut ColumnWriter) -> Result<()> {
+    use ColumnWriter::*;
+    match wtr {
+        BoolColumnWriter(wtr) => write_col_typed(iter, group_size, wtr),
+        Int32ColumnWriter(wtr) => write_col_typed(iter, group_size, wtr),
+        Int64ColumnWriter(wtr) => write_col_typed(iter, group_size, wtr),
+        Int96ColumnWriter(wtr) => write_col_typed(iter, group_size, wtr),
+        FloatColumnWriter(wtr) => write_col_typed(iter, group_size, wtr),
+        DoubleColumnWriter(wtr) => write_col_typed(iter, group_size, wtr),
+        ByteArrayColumnWriter(wtr) => write_col_typed(iter, group_size, wtr),
+        FixedLenByteArrayColumnWriter(wtr) => write_col_typed(iter, group_size, wtr),
+    }
+}
+
+fn write_col_typed<T>(
     iter: &mut rusqlite::Rows,
     group_size: usize,
     wtr: &mut parquet::column::writer::ColumnWriterImpl<T>,
@@ -221,18 +505,199 @@ where
     T: parquet::data_type::DataType,
     T::T: FromSqlite,
 {
-    let mut defs = vec![];
-    let mut vals = vec![];
+    let mut batch = Batch::<T>::new(wtr);
     for _ in 0..group_size {
         let x = match iter.get() {
             Some(x) => x,
             None => break,
         };
-        let x = x.get_ref(0)?;
-        if x == rusqlite::types::ValueRef::Null {
+        batch.push(x.get_ref(0)?)?;
+        iter.advance()?;
+    }
+    batch.write(wtr)
+}
+
+/// Writes the `idx`th value of each row
+fn write_col_from_rows(rows: &[Vec<Value>], idx: usize, wtr: &mut ColumnWriter) -> Result<()> {
+    use ColumnWriter::*;
+    match wtr {
+        BoolColumnWriter(wtr) => write_col_from_rows_typed(rows, idx, wtr),
+        Int32ColumnWriter(wtr) => write_col_from_rows_typed(rows, idx, wtr),
+        Int64ColumnWriter(wtr) => write_col_from_rows_typed(rows, idx, wtr),
+        Int96ColumnWriter(wtr) => write_col_from_rows_typed(rows, idx, wtr),
+        FloatColumnWriter(wtr) => write_col_from_rows_typed(rows, idx, wtr),
+        DoubleColumnWriter(wtr) => write_col_from_rows_typed(rows, idx, wtr),
+        ByteArrayColumnWriter(wtr) => write_col_from_rows_typed(rows, idx, wtr),
+        FixedLenByteArrayColumnWriter(wtr) => write_col_from_rows_typed(rows, idx, wtr),
+    }
+}
+
+fn write_col_from_rows_typed<T>(
+    rows: &[Vec<Value>],
+    idx: usize,
+    wtr: &mut parquet::column::writer::ColumnWriterImpl<T>,
+) -> Result<()>
+where
+    T: parquet::data_type::DataType,
+    T::T: FromSqlite,
+{
+    let mut batch = Batch::<T>::new(wtr);
+    for row in rows {
+        batch.push(ValueRef::from(&row[idx]))?;
+    }
+    batch.write(wtr)
+}
+
+/// Values and levels for a batch of rows, ready to be written to a column
+struct Batch<T: parquet::data_type::DataType> {
+    /// For list and map columns, each row contributes any number of values
+    repeated: Option<Repeated>,
+    /// The definition level of a non-null element
+    max_def: i16,
+    /// The definition level of an empty list or map
+    empty_def: i16,
+    /// The length of each value, for fixed-length byte arrays
+    type_len: usize,
+    /// Whether the values themselves (rather than the lists or maps
+    /// containing them) can be null
+    nullable: bool,
+    defs: Vec<i16>,
+    reps: Vec<i16>,
+    vals: Vec<T::T>,
+    /// The number of values which lost precision when converted
+    n_lossy: u64,
+}
+
+/// Which part of a JSON value is written to a repeated column
+#[derive(Clone, Copy, PartialEq)]
+enum Repeated {
+    /// The elements of an array
+    Elements,
+    /// The keys of an object
+    Keys,
+    /// The values of an object
+    Values,
+}
+
+impl<T> Batch<T>
+where
+    T: parquet::data_type::DataType,
+    T::T: FromSqlite,
+{
+    fn new(wtr: &parquet::column::writer::ColumnWriterImpl<T>) -> Batch<T> {
+        let descr = wtr.get_descriptor();
+        let parts = descr.path().parts();
+        let repeated = match parts {
+            _ if descr.max_rep_level() == 0 => None,
+            [.., kv, x] if kv == "key_value" && x == "key" => Some(Repeated::Keys),
+            [.., kv, x] if kv == "key_value" && x == "value" => Some(Repeated::Values),
+            _ => Some(Repeated::Elements),
+        };
+        // Map keys can't be null, so they have one level fewer
+        let nullable =
+            descr.self_type().get_basic_info().repetition() == parquet::basic::Repetition::OPTIONAL;
+        Batch {
+            repeated,
+            max_def: descr.max_def_level(),
+            empty_def: descr.max_def_level() - 1 - i16::from(nullable),
+            type_len: descr.type_length().max(0) as usize,
+            nullable,
+            defs: vec![],
+            reps: vec![],
+            vals: vec![],
+            n_lossy: 0,
+        }
+    }
+
+    /// Pushes a value, or for a list column, a JSON array of values
+    fn push(&mut self, x: ValueRef) -> Result<()> {
+        if self.repeated.is_none() {
+            return self.push_value(x);
+        }
+        let json: serde_json::Value = match x {
+            ValueRef::Null => return self.push_null(),
+            ValueRef::Text(x) | ValueRef::Blob(x) => {
+                serde_json::from_slice(x).context("Expected JSON")?
+            }
+            _ => bail!("Expected JSON, found {x:?}"),
+        };
+        match json {
+            serde_json::Value::Array(x) => self.push_elems(&x),
+            x => bail!("Expected a JSON array, found {x}"),
+        }
+    }
+
+    /// Pushes the keys or the values of a map, decoded by [`decode_map()`]
+    fn push_entries(&mut self, entries: Option<&[(String, serde_json::Value)]>) -> Result<()> {
+        let Some(entries) = entries else {
+            return self.push_null();
+        };
+        let elems = entries
+            .iter()
+            .map(|(k, v)| match self.repeated {
+                Some(Repeated::Keys) => serde_json::Value::from(k.as_str()),
+                _ => v.clone(),
+            })
+            .collect::<Vec<_>>();
+        self.push_elems(&elems)
+    }
+
+    // For an optional list, the levels are: 0 = null list, 1 = empty list,
+    // 2 = null element, 3 = element.  A required list has no level for
+    // "null list", so they're all one less.
+
+    /// Pushes a null list or map
+    fn push_null(&mut self) -> Result<()> {
+        if self.empty_def == 0 {
+            bail!("Null value in a required column");
+        }
+        self.defs.push(0);
+        self.reps.push(0);
+        Ok(())
+    }
+
+    /// Pushes the elements of a list or map
+    fn push_elems(&mut self, elems: &[serde_json::Value]) -> Result<()> {
+        if elems.is_empty() {
+            self.defs.push(self.empty_def);
+            self.reps.push(0);
+        }
+        for (i, elem) in elems.iter().enumerate() {
+            // Repetition level 0 starts a new row
+            self.reps.push(if i == 0 { 0 } else { 1 });
+            match conversion::json_to_value(elem) {
+                // One level less would mean an empty list, so a required
+                // value has no way to be null
+                Value::Null if !self.nullable => bail!("Null value in a required column"),
+                Value::Null => self.defs.push(self.max_def - 1),
+                x => {
+                    let x = self.convert(ValueRef::from(&x))?;
+                    self.defs.push(self.max_def);
+                    self.vals.push(x);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write(self, wtr: &mut parquet::column::writer::ColumnWriterImpl<T>) -> Result<()> {
+        if self.n_lossy > 0 {
+            tracing::warn!(
+                "{}: {} values lost precision as 32-bit floats",
+                wtr.get_descriptor().path().string(),
+                self.n_lossy,
+            );
+        }
+        let reps = self.repeated.map(|_| self.reps.as_slice());
+        wtr.write_batch(&self.vals, Some(&self.defs), reps).unwrap();
+        Ok(())
+    }
+
+    fn push_value(&mut self, x: ValueRef) -> Result<()> {
+        if x == ValueRef::Null {
             // This is an OPTIONAL column so the max definition level is 1.
             // This is less than that, so the value is null.
-            defs.push(0);
+            self.defs.push(0);
         } else {
             // If the column is OPTIONAL then the max definition level is 1.
             // This is equal to that, so the value is not null.
@@ -240,11 +705,176 @@ where
             // If the column is REQUIRED then the definition levels should
             // technically all be zeroes, but in that case the levels will
             // be discarded so it doesn't matter.
-            defs.push(1);
-            vals.push(T::T::from_sqlite(x)?);
+            self.defs.push(1);
+            let x = self.convert(x)?;
+            self.vals.push(x);
         }
-        iter.advance()?;
+        Ok(())
+    }
+
+    /// Converts a value, counting it if it loses precision
+    fn convert(&mut self, x: ValueRef) -> Result<T::T> {
+        let (x, lossy) = match T::get_physical_type() {
+            parquet::basic::Type::FIXED_LEN_BYTE_ARRAY => {
+                (T::T::from_sqlite_fixed(x, self.type_len)?, false)
+            }
+            _ => T::T::from_sqlite_lossy(x)?,
+        };
+        self.n_lossy += u64::from(lossy);
+        Ok(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, required: bool, physical_type: PhysicalType) -> Column {
+        Column {
+            name: name.to_string(),
+            required,
+            physical_type,
+            logical_type: None,
+            encoding: None,
+            dictionary: false,
+            query: String::new(),
+            list: false,
+            map: false,
+            values: vec![],
+        }
+    }
+
+    /// Writes the results of `sql` and reads them back
+    fn round_trip(sql: &str, cols: &[Column]) -> Result<Vec<Vec<Value>>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("t.parquet");
+        let conn = Connection::open_in_memory().unwrap();
+        let out = std::fs::File::create(&path).unwrap();
+        write_query(&conn, "t", sql, cols, &WriteOptions::default(), out, 2)?;
+        crate::read::ParquetFile::open(&path)?.rows().collect()
+    }
+
+    fn text(x: &str) -> Value {
+        Value::Text(x.to_string())
+    }
+
+    #[test]
+    fn list_levels() {
+        let cols = [Column {
+            list: true,
+            ..column("xs", false, PhysicalType::Int64)
+        }];
+        let sql = "VALUES ('[1, null, 2]'), ('[]'), (NULL), ('[null]'), ('[3]')";
+        let rows = round_trip(sql, &cols).unwrap();
+        let expected = [
+            text("[1,null,2]"),
+            text("[]"),
+            Value::Null,
+            text("[null]"),
+            text("[3]"),
+        ];
+        assert_eq!(rows, expected.map(|x| vec![x]));
+
+        // A required list has no level for "null list"
+        let cols = [Column {
+            list: true,
+            ..column("xs", true, PhysicalType::Int64)
+        }];
+        let rows = round_trip("VALUES ('[]'), ('[null]')", &cols).unwrap();
+        assert_eq!(rows, [[text("[]")], [text("[null]")]]);
+        assert!(round_trip("VALUES (NULL)", &cols).is_err());
+    }
+
+    #[test]
+    fn map_levels() {
+        let cols = [Column {
+            map: true,
+            ..column("m", false, PhysicalType::Int64)
+        }];
+        let sql = r#"VALUES ('{"a": 1, "b": null}'), ('{}'), (NULL)"#;
+        let rows = round_trip(sql, &cols).unwrap();
+        let expected = [text(r#"{"a":1,"b":null}"#), text("{}"), Value::Null];
+        assert_eq!(rows, expected.map(|x| vec![x]));
+    }
+
+    #[test]
+    fn map_columns() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE entities (id INTEGER PRIMARY KEY, name TEXT);
+            CREATE TABLE attrs (entity_id INTEGER, key TEXT, value INTEGER);
+            INSERT INTO entities VALUES (1, 'a'), (2, 'b'), (3, 'c');
+            INSERT INTO attrs VALUES (3, 'y', 4), (1, 'z', 1), (1, 'x', 2), (3, 'x', 3);",
+        )
+        .unwrap();
+        let kv = KeyValueTable {
+            table: "attrs".to_string(),
+            foreign_key: "entity_id".to_string(),
+            references: None,
+            key: "key".to_string(),
+            value: "value".to_string(),
+        };
+        let mut cols = infer_schema(&conn, "entities")
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let map = infer_map_column(&conn, "entities", "attrs", &kv, &Default::default());
+        cols.push(map.unwrap());
+        cols.push(Column {
+            name: "name2".to_string(),
+            ..cols[1].clone()
+        });
+
+        // The groups split the rows up differently from the columns
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("entities.parquet");
+        let out = std::fs::File::create(&path).unwrap();
+        write_table(&conn, "entities", &cols, &out, 2).unwrap();
+        let rows = crate::read::ParquetFile::open(&path)
+            .unwrap()
+            .rows()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let expected = [
+            (1, "a", r#"{"x":2,"z":1}"#),
+            (2, "b", "{}"),
+            (3, "c", r#"{"x":3,"y":4}"#),
+        ];
+        let expected = expected
+            .map(|(id, name, map)| vec![Value::Integer(id), text(name), text(map), text(name)]);
+        assert_eq!(rows, expected);
+
+        conn.execute("INSERT INTO attrs VALUES (2, 'w', x'00')", [])
+            .unwrap();
+        let err = infer_map_column(&conn, "entities", "attrs", &kv, &Default::default());
+        let err = format!("{:#}", err.unwrap_err());
+        assert!(err.contains("Map values can't be blobs"), "{err}");
+    }
+
+    #[test]
+    fn list_of_structs_levels() {
+        let cols = [
+            column("items[].sku", false, PhysicalType::ByteArray),
+            column("items[].qty", false, PhysicalType::Int64),
+        ];
+        let sql = r#"VALUES ('["x", "y"]', '[1, null]'), ('[]', '[]'), (NULL, NULL)"#;
+        let rows = round_trip(sql, &cols).unwrap();
+        assert_eq!(
+            rows,
+            [
+                [text(r#"["x","y"]"#), text("[1,null]")],
+                [text("[]"), text("[]")],
+                [Value::Null, Value::Null],
+            ]
+        );
+
+        // `[{"qty": null}]` can't be written if qty is required: the level
+        // for a null qty would mean an empty list instead
+        let cols = [column("items[].qty", true, PhysicalType::Int64)];
+        let err = round_trip("VALUES ('[null]')", &cols).unwrap_err();
+        assert!(format!("{err:#}").contains("Null value in a required column"));
+        let cols = [column("items[].qty", false, PhysicalType::Int64)];
+        let rows = round_trip("VALUES ('[null]')", &cols).unwrap();
+        assert_eq!(rows, [[text("[null]")]]);
     }
-    wtr.write_batch(&vals, Some(&defs), None).unwrap();
-    Ok(())
 }
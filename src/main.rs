@@ -1,103 +1,528 @@
-use anyhow::Result;
-use clap::Parser;
-use rusqlite::Connection;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use rusqlite::{Connection, OptionalExtension};
 use sqlite2parquet::*;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
-/// Extracts data from a sqlite3 DB and writes to parquet files.
-///
-/// One parquet file will be creates per table in the DB.  If you want to
-/// select which tables to extract, use the --table flag.  It can be passed
-/// multiple times to specify a set of tables.  If --table is not pass,
-/// all tables will be extracted.
-///
-/// We do our best to guess a good encoding for each column.  This is mostly
-/// done based on the SQL schema.  More advanced analysis is planned.
-///
-/// The output directory will be created if it doesn't exist.  If it already
-/// contains parquet files with conflicting names, those files will be
-/// overwritten.
+/// Like `println!`, but only when the output format is text
+macro_rules! say {
+    ($rep:expr, $($arg:tt)*) => {
+        if $rep.format == OutputFormat::Text {
+            println!($($arg)*)
+        }
+    };
+}
+
+/// Converts sqlite3 databases to parquet files, and back again.
 ///
-/// Rows will be written in batches.  This is mostly to ensure that
-/// sqlite2parquet can run in constant memory.  You can choose the batch
-/// size with --group-size.  Increasing this number improves the amount of
-/// compression we're able to achieve, but will cause sqlite2parquet to use
-/// more memory.
+/// If no subcommand is given, `export` is assumed: eg.
+/// `sqlite2parquet db.sqlite out/` is the same as
+/// `sqlite2parquet export db.sqlite out/`.
 #[derive(Parser)]
+#[structopt(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Opts {
+    #[structopt(subcommand)]
+    pub cmd: Option<Cmd>,
+    #[structopt(flatten)]
+    pub export: ExportOpts,
+}
+
+#[derive(Subcommand)]
+pub enum Cmd {
+    /// Extracts data from a sqlite3 DB and writes to parquet files
+    ///
+    /// One parquet file will be creates per table in the DB.  If you want to
+    /// select which tables to extract, use the --table flag.  It can be passed
+    /// multiple times to specify a set of tables.  If --table is not pass,
+    /// all tables will be extracted.
+    ///
+    /// We do our best to guess a good encoding for each column.  This is mostly
+    /// done based on the SQL schema.  More advanced analysis is planned.
+    ///
+    /// The output directory will be created if it doesn't exist.  If it already
+    /// contains parquet files with conflicting names, those files will be
+    /// overwritten.  Files are written under a temporary name and moved into
+    /// place once they're complete, so readers never see a half-written file.
+    ///
+    /// Rows will be written in batches.  This is mostly to ensure that
+    /// sqlite2parquet can run in constant memory.  You can choose the batch
+    /// size with --group-size.  Increasing this number improves the amount of
+    /// compression we're able to achieve, but will cause sqlite2parquet to use
+    /// more memory.
+    ///
+    /// With --resume, the completed tables are recorded in a manifest in the
+    /// output directory.  Re-running with --resume skips tables which were
+    /// completed by a previous run, as long as their contents haven't changed
+    /// since.  To check this, each table (and any table which map or
+    /// denormalized columns come from) is hashed, which means reading it
+    /// again; but that's much quicker than exporting it.
+    ///
+    /// If built with the "cdc" feature, --delta writes only the changes made
+    /// since the last export, as a numbered delta file next to the existing
+    /// snapshot.  --compact merges the deltas back into the snapshot.
+    Export(ExportOpts),
+    /// Prints the schema which would be used to export each table
+    ///
+    /// With --output, the schema is written to a file in the format expected
+    /// by `export --config`, so you can tweak it before exporting.
+    Infer(InferOpts),
+    /// Describes existing parquet files
+    ///
+    /// Prints the schema, the encodings used, the size of each column
+    /// before and after compression, the row groups, the statistics
+    /// recorded for each column, and any key-value metadata.  Only the
+    /// file footers are read.
+    Inspect(InspectOpts),
+    /// Checks exported parquet files against the source database
+    ///
+    /// Each file is read back and compared with its table: row counts, null
+    /// counts, min/max of each column, and a hash of the content.  Any
+    /// columns or ranges of rows which differ are reported.
+    Verify(VerifyOpts),
+    /// Loads exported parquet files back into a sqlite3 DB
+    ///
+    /// One table is created per parquet file.  If the export included
+    /// `sqlite_schema` (see `export --include-schema`), the tables are
+    /// created with their original definitions, and the indices, views, and
+    /// triggers are re-created once the data has been loaded.  Otherwise
+    /// the column types are derived from the parquet schema.
+    Restore(RestoreOpts),
+    /// Merges parquet files which share a schema into a single file
+    ///
+    /// This is useful for tidying up the small files left behind by
+    /// incremental or partitioned exports.  The rows are re-split into
+    /// groups of the given size.
+    Merge(MergeOpts),
+}
+
+#[derive(Parser)]
+pub struct ExportOpts {
     /// The sqlite3 database to read from
-    pub sqlite: PathBuf,
+    #[structopt(required = true)]
+    pub sqlite: Option<PathBuf>,
     /// The directory to put parquet files in
-    pub out_dir: PathBuf,
+    #[structopt(required = true)]
+    pub out_dir: Option<PathBuf>,
+    /// A YAML file giving the columns to write for some tables (under
+    /// `tables:`), queries to export (`queries:`), map columns (`maps:`),
+    /// columns from related tables (`denormalize:`), and which tables to
+    /// export (`include:`, `exclude:`, etc.)
     #[structopt(long)]
     pub config: Option<PathBuf>,
     /// The table(s) to extract
     #[structopt(long, short)]
     pub table: Vec<String>,
+    #[structopt(flatten)]
+    pub selection: Selection,
+    /// Export the results of this query.  Unless some tables are also
+    /// selected, only the queries are exported.  Each query needs a `--name`.
+    #[structopt(long, requires = "name")]
+    pub query: Vec<String>,
+    /// The name to give the results of the corresponding `--query`
+    #[structopt(long, requires = "query")]
+    pub name: Vec<String>,
     /// The size of each row group
     #[structopt(long, short, default_value = "1000000")]
     pub group_size: usize,
     #[structopt(long)]
     pub include_schema: bool,
+    /// Skip tables which were completely exported by a previous run
+    #[structopt(long)]
+    pub resume: bool,
+    /// Count the rows in each table before exporting it.  By default the
+    /// counts are estimated, which is much faster for large tables but makes
+    /// the progress display less accurate.
+    #[structopt(long)]
+    pub exact_count: bool,
+    #[structopt(flatten)]
+    pub infer: InferArgs,
+    /// Follow the foreign keys of tables which don't have a `denormalize`
+    /// entry in the config: add the columns of the tables each one refers
+    /// to, and nest the rows of the tables which refer to it
+    #[structopt(long)]
+    pub denormalize: bool,
+    /// Make the output files depend only on the data: running the same
+    /// export again, even with a different version of sqlite2parquet, gives
+    /// byte-identical files (as long as the inferred schema doesn't change)
+    #[structopt(long)]
+    pub reproducible: bool,
+    /// Write the changes since the last export as a delta file.  Tables
+    /// which haven't been exported yet are exported in full.
+    #[cfg(feature = "cdc")]
+    #[structopt(long, conflicts_with = "compact")]
+    pub delta: bool,
+    /// Merge each table's delta files into its snapshot
+    #[cfg(feature = "cdc")]
+    #[structopt(long)]
+    pub compact: bool,
+    /// With "json", progress and a summary of each table are printed to
+    /// stdout as newline-delimited JSON
+    #[structopt(long, value_enum, default_value = "text")]
+    pub output_format: OutputFormat,
+}
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Parser)]
+pub struct InferOpts {
+    /// The sqlite3 database to read from
+    pub sqlite: PathBuf,
+    /// The table(s) to infer a schema for
+    #[structopt(long, short)]
+    pub table: Vec<String>,
+    #[structopt(flatten)]
+    pub selection: Selection,
+    /// Write the schema to this file, as YAML
+    #[structopt(long, short)]
+    pub output: Option<PathBuf>,
+    #[structopt(flatten)]
+    pub infer: InferArgs,
+}
+
+/// Patterns which pick out tables by name.  These apply when no tables are
+/// named explicitly with `--table`.
+///
+/// They can also be given in the config file, under the same names.
+#[derive(Parser, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct Selection {
+    /// Only use tables whose names match this glob (eg. "events_*")
+    #[structopt(long)]
+    pub include: Vec<String>,
+    /// Skip tables whose names match this glob (eg. "*_tmp")
+    #[structopt(long)]
+    pub exclude: Vec<String>,
+    /// Only use tables whose names contain a match for this regex
+    #[structopt(long)]
+    pub include_regex: Vec<String>,
+    /// Skip tables whose names contain a match for this regex
+    #[structopt(long)]
+    pub exclude_regex: Vec<String>,
+}
+
+/// Options which control schema inference
+#[derive(Parser)]
+pub struct InferArgs {
+    /// Infer the schema from about this many rows, spread evenly through
+    /// each table, instead of reading the whole table.  Columns are then
+    /// only required if they're declared `NOT NULL`.
+    #[structopt(long)]
+    pub infer_sample: Option<u64>,
+    /// The number of rows to sample when deciding whether to use dictionary
+    /// encoding
+    #[structopt(long, default_value = "1000")]
+    pub sample_size: usize,
+    /// Seeds the choice of rows to sample.  The same seed always gives the
+    /// same schema.
+    #[structopt(long, default_value = "0")]
+    pub seed: u64,
+    /// Use dictionary encoding for columns where the proportion of distinct
+    /// values in the sample is less than this
+    #[structopt(long, default_value = "0.75")]
+    pub dictionary_threshold: f64,
+    /// Split JSON columns whose values are objects into a column per field,
+    /// written as a struct.  The fields are taken from the sampled rows.
+    #[structopt(long)]
+    pub shred_json: bool,
+    /// Write text columns with at most this many distinct values as enums.
+    /// Columns with a `CHECK (x IN (...))` constraint always are.
+    #[structopt(long)]
+    pub enum_max_values: Option<usize>,
+    /// Parse booleans leniently: any non-zero number is true, and so are
+    /// texts like "true", "yes", and "t", and text columns which only hold
+    /// those are written as booleans.  Without this, BOOL columns must hold
+    /// 0 or 1.  The original text isn't kept, so no sha3sum is recorded,
+    /// and `verify` reports how many values were parsed this way.
+    #[structopt(long)]
+    pub lenient_bools: bool,
+}
+
+impl InferArgs {
+    fn options(&self) -> InferOptions {
+        InferOptions {
+            max_rows: self.infer_sample,
+            sample_size: self.sample_size,
+            seed: self.seed,
+            dictionary_threshold: self.dictionary_threshold,
+            shred_json: self.shred_json,
+            enum_max_values: self.enum_max_values,
+            lenient_bools: self.lenient_bools,
+        }
+    }
+}
+
+#[derive(Parser)]
+pub struct RestoreOpts {
+    /// The sqlite3 database to write to.  It will be created if it doesn't
+    /// exist.
+    pub sqlite: PathBuf,
+    /// The directory containing the parquet files
+    pub out_dir: PathBuf,
+    /// The table(s) to restore
+    #[structopt(long, short)]
+    pub table: Vec<String>,
+}
+
+#[derive(Parser)]
+pub struct MergeOpts {
+    /// The parquet files to merge
+    #[structopt(required = true)]
+    pub inputs: Vec<PathBuf>,
+    /// The file to write
+    #[structopt(long, short)]
+    pub output: PathBuf,
+    /// The size of each row group
+    #[structopt(long, short, default_value = "1000000")]
+    pub group_size: usize,
+}
+
+#[derive(Parser)]
+pub struct VerifyOpts {
+    /// The sqlite3 database which was exported
+    pub sqlite: PathBuf,
+    /// The directory containing the parquet files
+    pub out_dir: PathBuf,
+    /// The config which was used for the export, if any
+    #[structopt(long)]
+    pub config: Option<PathBuf>,
+    /// The table(s) to check
+    #[structopt(long, short)]
+    pub table: Vec<String>,
+    #[structopt(flatten)]
+    pub selection: Selection,
+    /// Pass this if the export used --denormalize
+    #[structopt(long)]
+    pub denormalize: bool,
+}
+
+#[derive(Parser)]
+pub struct InspectOpts {
+    /// The parquet files to inspect
+    #[structopt(required = true)]
+    pub files: Vec<PathBuf>,
+    /// Print the information as JSON, one object per file
+    #[structopt(long)]
+    pub json: bool,
 }
 
 fn main() -> anyhow::Result<()> {
     let opts = Opts::parse();
-    tracing_subscriber::fmt::init();
+    // Logs go to stderr so that they don't get mixed up with JSON output
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::IsTerminal::is_terminal(&std::io::stderr()))
+        .init();
+    match opts.cmd {
+        Some(Cmd::Export(opts)) => export(opts),
+        Some(Cmd::Infer(opts)) => infer(opts),
+        Some(Cmd::Inspect(opts)) => inspect(opts),
+        Some(Cmd::Verify(opts)) => verify(opts),
+        Some(Cmd::Restore(opts)) => restore(opts),
+        Some(Cmd::Merge(opts)) => merge(opts),
+        None => export(opts.export),
+    }
+}
 
-    let mut config: HashMap<String, Vec<Column>> = if let Some(path) = opts.config {
-        serde_yaml::from_reader(std::fs::File::open(path)?)?
-    } else {
-        HashMap::default()
-    };
+fn export(opts: ExportOpts) -> Result<()> {
+    // clap makes sure these are present
+    let sqlite = opts.sqlite.expect("required");
+    let out_dir = opts.out_dir.expect("required");
 
-    let conn = rusqlite::Connection::open(&opts.sqlite)?;
+    let Config {
+        selection,
+        mut queries,
+        maps,
+        denormalize,
+        tables: mut config,
+        ..
+    } = Config::load(opts.config.as_deref())?;
+    if opts.query.len() != opts.name.len() {
+        anyhow::bail!("Each --query needs a --name");
+    }
+    // Queries on the command line replace the default of exporting every
+    // table
+    let only_queries = !opts.query.is_empty()
+        && opts.table.is_empty()
+        && opts.selection.is_empty()
+        && selection.is_empty()
+        && config.is_empty();
+    queries.extend(opts.name.into_iter().zip(opts.query));
 
-    let mut tables: Vec<String> = if !opts.table.is_empty() {
-        opts.table
-    } else if !config.is_empty() {
-        config.keys().cloned().collect()
+    let conn = rusqlite::Connection::open(&sqlite)?;
+    let rep = Reporter::new(opts.output_format);
+    let settings = Settings {
+        group_size: opts.group_size.max(1),
+        infer: opts.infer.options(),
+        created_by: opts.reproducible.then(|| "sqlite2parquet".to_string()),
+        related: Related {
+            maps,
+            denormalize,
+            all_foreign_keys: opts.denormalize,
+        },
+    };
+
+    let mut tables = if only_queries {
+        vec![]
     } else {
-        let mut table_info = conn.prepare(
-            "SELECT name
-            FROM sqlite_schema
-            WHERE type = 'table'
-            AND name NOT LIKE 'sqlite_%'",
-        )?;
-        let x = table_info
-            .query_map([], |row| row.get::<_, String>(0))?
-            .collect::<rusqlite::Result<_>>()?;
-        x
+        select_tables(&conn, opts.table, &config, opts.selection.and(selection))?
     };
+    if !only_queries {
+        say!(
+            rep,
+            "Selected {} tables: {}",
+            tables.len(),
+            tables.join(", ")
+        );
+    }
+    rep.event(serde_json::json!({ "event": "selected", "tables": tables }))?;
     if opts.include_schema {
         tables.push("sqlite_schema".to_string());
     }
 
-    std::fs::create_dir_all(&opts.out_dir)?;
-    for table in tables {
-        let out = opts.out_dir.join(format!("{}.parquet", &table));
+    std::fs::create_dir_all(&out_dir)?;
+    remove_stale_tmp_files(&out_dir, rep)?;
+    let manifest_path = out_dir.join(MANIFEST);
+    let mut manifest = if opts.resume && manifest_path.exists() {
+        serde_yaml::from_reader(std::fs::File::open(&manifest_path)?)?
+    } else {
+        Manifest::default()
+    };
+
+    // Count everything up-front so we can show progress for the whole run
+    if rep.format == OutputFormat::Text && !tables.is_empty() {
+        print!("Counting rows in {} tables...", tables.len());
+        std::io::stdout().flush()?;
+    }
+    let counts = tables
+        .iter()
+        .map(|table| {
+            let config = config.get(table).map(|x| x.as_slice());
+            count_rows(&conn, table, config, opts.exact_count)
+        })
+        .collect::<Result<Vec<RowCount>>>()?;
+    let mut overall = Overall::new(tables.len(), counts.iter().map(|x| x.n_rows).sum());
+    let all_exact = counts.iter().all(|x| x.exact);
+    if !tables.is_empty() {
+        say!(
+            rep,
+            " {}{}",
+            if all_exact { "" } else { "~" },
+            overall.rows_total
+        );
+    }
+    rep.event(serde_json::json!({
+        "event": "counted",
+        "tables": tables.iter().zip(&counts).map(|(table, count)| {
+            serde_json::json!({ "table": table, "rows": count.n_rows, "exact": count.exact })
+        }).collect::<Vec<_>>(),
+        "rows_total": overall.rows_total,
+        "exact": all_exact,
+    }))?;
+
+    for (table, count) in tables.into_iter().zip(counts) {
+        overall.start_table(count);
+        let out = out_dir.join(format!("{}.parquet", &table));
+        #[cfg(feature = "cdc")]
+        if opts.delta && out.exists() {
+            mk_delta(&conn, &table, &out, &out_dir, opts.group_size, rep)?;
+            overall.finish_table(count.n_rows);
+            continue;
+        }
+        #[cfg(feature = "cdc")]
+        if opts.compact {
+            compact_table(&table, &out, &out_dir, opts.group_size, rep)?;
+            overall.finish_table(count.n_rows);
+            continue;
+        }
         let config = config.remove(&table);
-        mk_table(&conn, &table, &out, config, opts.group_size)?;
+        if opts.resume {
+            let source = Source::new(&conn, &table, config.as_deref(), &settings.related)?;
+            if manifest.is_complete(&table, &source, &out) {
+                say!(rep, "Skipping {table}: already exported");
+                rep.event(serde_json::json!({ "event": "skipped", "table": table }))?;
+                // Don't count the skipped rows towards the rate.  This takes
+                // them out of the total too.
+                overall.finish_table(0);
+                continue;
+            }
+            // We've just hashed the table, so there's no need to do it again
+            let sha3sum = config.is_none().then(|| source.sha3sum.clone());
+            let n_rows = mk_table(
+                &conn, &table, &out, config, sha3sum, &settings, rep, &overall,
+            )?;
+            overall.finish_table(n_rows);
+            manifest
+                .tables
+                .insert(table, ManifestEntry { n_rows, source });
+            manifest.save(&manifest_path)?;
+        } else {
+            let n_rows = mk_table(&conn, &table, &out, config, None, &settings, rep, &overall)?;
+            overall.finish_table(n_rows);
+        }
+    }
+    let mut query_rows = 0;
+    for (name, sql) in &queries {
+        let out = out_dir.join(format!("{name}.parquet"));
+        query_rows += mk_query(&conn, name, sql, &out, &settings, rep)?;
     }
+    let elapsed = overall.t_start.elapsed();
+    if queries.is_empty() {
+        say!(
+            rep,
+            "Exported {} rows from {} tables in {:.1?}",
+            overall.rows_done,
+            overall.n_tables,
+            elapsed,
+        );
+    } else {
+        say!(
+            rep,
+            "Exported {} rows from {} tables and {} queries in {:.1?}",
+            overall.rows_done + query_rows,
+            overall.n_tables,
+            queries.len(),
+            elapsed,
+        );
+    }
+    rep.event(serde_json::json!({
+        "event": "finished",
+        "tables": overall.n_tables,
+        "rows": overall.rows_done,
+        "elapsed_secs": elapsed.as_secs_f64(),
+    }))?;
     Ok(())
 }
 
-const COLUMN_HEADER: &str =
-    "Column                 Physical type   Encoding             Logical type               SQL";
+/// The number of rows in a table, which may only be an estimate
+#[derive(Clone, Copy)]
+struct RowCount {
+    n_rows: u64,
+    exact: bool,
+}
 
-fn mk_table(
+fn count_rows(
     conn: &Connection,
     table: &str,
-    outpath: &Path,
-    // Infer if `None`
-    config: Option<Vec<Column>>,
-    group_size: usize,
-) -> Result<()> {
-    print!("Counting rows...");
-    std::io::stdout().flush()?;
-    let n_rows: u64 = if let Some(config) = config.as_ref() {
+    config: Option<&[Column]>,
+    exact: bool,
+) -> Result<RowCount> {
+    // Custom queries usually read from the table they're named after, so
+    // that's a good enough estimate for them too
+    if !exact {
+        if let Some(n_rows) = estimate_rows(conn, table) {
+            return Ok(RowCount {
+                n_rows,
+                exact: false,
+            });
+        }
+    }
+    let n_rows = if let Some(config) = config {
         conn.query_row(
             &format!("SELECT COUNT(1) FROM ({})", config[0].query),
             [],
@@ -108,117 +533,1329 @@ fn mk_table(
             row.get(0)
         })?
     };
-    println!(" {n_rows}");
+    Ok(RowCount {
+        n_rows,
+        exact: true,
+    })
+}
 
-    let cols: Vec<Column> = if let Some(cols) = config {
-        println!("    {}", COLUMN_HEADER);
-        for col in &cols {
-            println!("    {}", col);
+/// Estimates the number of rows in a table without scanning it, or returns
+/// `None` if we can't
+fn estimate_rows(conn: &Connection, table: &str) -> Option<u64> {
+    // Accurate as of the last time ANALYZE was run.  The first number in
+    // `stat` is the number of rows.
+    let stat1 = conn
+        .query_row(
+            "SELECT stat FROM sqlite_stat1 WHERE tbl = ?1 LIMIT 1",
+            [table],
+            |x| x.get::<_, String>(0),
+        )
+        .ok()
+        .and_then(|x| x.split(' ').next()?.parse().ok());
+    // Accurate unless rows have been deleted
+    let max_rowid = || {
+        conn.query_row(&format!("SELECT MAX(rowid) FROM {table}"), [], |x| {
+            x.get::<_, Option<i64>>(0)
+        })
+        .ok()
+        .map(|x| x.unwrap_or(0).max(0) as u64)
+    };
+    // For WITHOUT ROWID tables, the rows are the cells in the leaf pages.
+    // This needs sqlite to be built with SQLITE_ENABLE_DBSTAT_VTAB.
+    let dbstat = || {
+        conn.query_row(
+            "SELECT SUM(ncell) FROM dbstat WHERE name = ?1 AND pagetype = 'leaf'",
+            [table],
+            |x| x.get::<_, Option<i64>>(0),
+        )
+        .ok()
+        .flatten()
+        .map(|x| x.max(0) as u64)
+    };
+    stat1.or_else(max_rowid).or_else(dbstat)
+}
+
+/// Progress across all the tables being exported
+struct Overall {
+    t_start: std::time::Instant,
+    n_tables: usize,
+    /// The number of tables finished or skipped
+    tables_done: usize,
+    rows_total: u64,
+    /// The number of rows in the finished tables
+    rows_done: u64,
+    /// The number of rows we expect in the current table
+    table_rows: u64,
+    /// False if `table_rows` is only an estimate
+    table_rows_exact: bool,
+}
+
+impl Overall {
+    fn new(n_tables: usize, rows_total: u64) -> Overall {
+        Overall {
+            t_start: std::time::Instant::now(),
+            n_tables,
+            tables_done: 0,
+            rows_total,
+            rows_done: 0,
+            table_rows: 0,
+            table_rows_exact: true,
+        }
+    }
+
+    fn start_table(&mut self, count: RowCount) {
+        self.table_rows = count.n_rows;
+        self.table_rows_exact = count.exact;
+    }
+
+    fn finish_table(&mut self, n_rows: u64) {
+        // The table may have changed since we counted it
+        self.rows_total = self.rows_total - self.table_rows.min(self.rows_total) + n_rows;
+        self.rows_done += n_rows;
+        self.tables_done += 1;
+        self.table_rows = 0;
+    }
+
+    /// Works out the percentage done, throughput, and time remaining, given
+    /// the number of rows written in the current table
+    fn stats(&self, table_rows_written: u64) -> OverallStats {
+        let rows = self.rows_done + table_rows_written.min(self.table_rows);
+        let elapsed = self.t_start.elapsed().as_secs_f64();
+        let rate = rows as f64 / elapsed;
+        let remaining = self.rows_total.saturating_sub(rows);
+        OverallStats {
+            rows,
+            pc: rows as f64 / self.rows_total.max(1) as f64 * 100.0,
+            rate,
+            eta: (rate > 0.0).then(|| std::time::Duration::from_secs_f64(remaining as f64 / rate)),
         }
-        cols
+    }
+}
+
+struct OverallStats {
+    rows: u64,
+    pc: f64,
+    rate: f64,
+    eta: Option<std::time::Duration>,
+}
+
+fn list_tables(conn: &Connection) -> Result<Vec<String>> {
+    let mut table_info = conn.prepare(
+        "SELECT name, sql
+        FROM sqlite_schema
+        WHERE type = 'table'
+        AND name NOT LIKE 'sqlite_%'",
+    )?;
+    let tables = table_info
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    // FTS and rtree tables keep their data in "shadow" tables.  Exporting
+    // the virtual table itself is enough.
+    let shadows = tables
+        .iter()
+        .filter_map(|(name, sql)| {
+            let suffixes: &[&str] = match virtual_table_module(sql.as_deref()?)?.as_str() {
+                "fts3" | "fts4" => &["content", "segments", "segdir", "docsize", "stat"],
+                "fts5" => &["data", "idx", "content", "docsize", "config"],
+                "rtree" | "rtree_i32" | "geopoly" => &["node", "rowid", "parent"],
+                _ => &[],
+            };
+            Some(suffixes.iter().map(move |x| format!("{name}_{x}")))
+        })
+        .flatten()
+        .collect::<Vec<_>>();
+    Ok(tables
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|name| !shadows.contains(name))
+        .collect())
+}
+
+/// The (lowercase) module name from a `CREATE VIRTUAL TABLE` statement
+fn virtual_table_module(sql: &str) -> Option<String> {
+    let upper = sql.to_ascii_uppercase();
+    if !upper.starts_with("CREATE VIRTUAL TABLE") {
+        return None;
+    }
+    let rest = sql[upper.find(" USING ")? + " USING ".len()..].trim_start();
+    let module = rest
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()?;
+    Some(module.to_ascii_lowercase())
+}
+
+/// Decides which tables to work on.  Tables named on the command line are
+/// used as-is.  Otherwise, the tables defined in the config are used; or, if
+/// there are none (or if there are any patterns), all the tables in the DB.
+/// Finally, the patterns are applied.
+fn select_tables(
+    conn: &Connection,
+    named: Vec<String>,
+    config: &BTreeMap<String, Vec<Column>>,
+    selection: Selection,
+) -> Result<Vec<String>> {
+    if !named.is_empty() {
+        return Ok(named);
+    }
+    let mut tables = config.keys().cloned().collect::<Vec<_>>();
+    if tables.is_empty() || !selection.is_empty() {
+        for table in list_tables(conn)? {
+            if !tables.contains(&table) {
+                tables.push(table);
+            }
+        }
+    }
+    let include_regex = compile_regexes(&selection.include_regex)?;
+    let exclude_regex = compile_regexes(&selection.exclude_regex)?;
+    let glob = |pattern: &str, name: &str| -> Result<bool> {
+        // Use sqlite's GLOB operator, so patterns mean the same as in SQL
+        Ok(conn.query_row("SELECT ?1 GLOB ?2", [name, pattern], |x| x.get(0))?)
+    };
+    let mut selected = vec![];
+    for table in tables {
+        let mut included = selection.include.is_empty() && include_regex.is_empty();
+        for pattern in &selection.include {
+            included |= glob(pattern, &table)?;
+        }
+        included |= include_regex.iter().any(|x| x.is_match(&table));
+        for pattern in &selection.exclude {
+            included &= !glob(pattern, &table)?;
+        }
+        included &= !exclude_regex.iter().any(|x| x.is_match(&table));
+        if included {
+            selected.push(table);
+        }
+    }
+    Ok(selected)
+}
+
+fn compile_regexes(patterns: &[String]) -> Result<Vec<regex::Regex>> {
+    patterns
+        .iter()
+        .map(|x| regex::Regex::new(x).with_context(|| format!("Bad regex: {x}")))
+        .collect()
+}
+
+impl Selection {
+    fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.include_regex.is_empty()
+            && self.exclude_regex.is_empty()
+    }
+
+    /// Combines the patterns from the command line and the config file
+    fn and(mut self, other: Selection) -> Selection {
+        self.include.extend(other.include);
+        self.exclude.extend(other.exclude);
+        self.include_regex.extend(other.include_regex);
+        self.exclude_regex.extend(other.exclude_regex);
+        self
+    }
+}
+
+type Maps = BTreeMap<String, BTreeMap<String, KeyValueTable>>;
+
+/// Columns which are added to a table's export from other tables
+struct Related {
+    maps: Maps,
+    denormalize: BTreeMap<String, Denormalize>,
+    /// Follow all the foreign keys of tables which aren't in `denormalize`
+    all_foreign_keys: bool,
+}
+
+impl Related {
+    /// The relationships to follow for `table`, if any
+    fn denormalize(&self, conn: &Connection, table: &str) -> Result<Option<Denormalize>> {
+        Ok(match self.denormalize.get(table) {
+            Some(x) => Some(x.clone()),
+            None if self.all_foreign_keys => Some(Denormalize::all(conn, table)?),
+            None => None,
+        })
+    }
+
+    fn columns(&self, conn: &Connection, table: &str, opts: &InferOptions) -> Result<Vec<Column>> {
+        let mut cols = match self.denormalize(conn, table)? {
+            Some(denorm) => sqlite2parquet::infer_related_columns(conn, table, &denorm, opts)?,
+            None => vec![],
+        };
+        for (name, kv) in self.maps.get(table).into_iter().flatten() {
+            cols.push(sqlite2parquet::infer_map_column(
+                conn, table, name, kv, opts,
+            )?);
+        }
+        Ok(cols)
+    }
+
+    /// The tables which the extra columns for `table` are taken from
+    fn tables(&self, conn: &Connection, table: &str) -> Result<Vec<String>> {
+        let mut tables = vec![];
+        if let Some(denorm) = self.denormalize(conn, table)? {
+            tables.extend(denorm.parents.into_keys());
+            tables.extend(denorm.children);
+        }
+        for kv in self.maps.get(table).into_iter().flat_map(|x| x.values()) {
+            tables.push(kv.table.clone());
+        }
+        tables.sort();
+        tables.dedup();
+        Ok(tables)
+    }
+}
+
+/// The contents of a `--config` file
+#[derive(Default, serde::Deserialize)]
+struct Config {
+    #[serde(flatten)]
+    selection: Selection,
+    /// Queries to export, by name
+    #[serde(default)]
+    queries: BTreeMap<String, String>,
+    /// Key/value tables to fold into map columns, by parent table and then
+    /// by column name
+    #[serde(default)]
+    maps: Maps,
+    /// The related tables to pull columns from, by table
+    #[serde(default)]
+    denormalize: BTreeMap<String, Denormalize>,
+    /// The columns to write, by table
+    #[serde(default)]
+    tables: BTreeMap<String, Vec<Column>>,
+    /// Column configs used to go at the top level, which meant they couldn't
+    /// be given for tables with the same names as the other keys.  They're
+    /// still accepted, and moved into `tables` when loading.
+    #[serde(flatten)]
+    top_level_tables: BTreeMap<String, Vec<Column>>,
+}
+
+impl Config {
+    fn load(path: Option<&Path>) -> Result<Config> {
+        let Some(path) = path else {
+            return Ok(Config::default());
+        };
+        let file = std::fs::File::open(path).with_context(|| path.display().to_string())?;
+        let mut config: Config =
+            serde_yaml::from_reader(file).with_context(|| path.display().to_string())?;
+        for (table, cols) in std::mem::take(&mut config.top_level_tables) {
+            if config.tables.contains_key(&table) {
+                anyhow::bail!("{}: {table} is configured twice", path.display());
+            }
+            config.tables.insert(table, cols);
+        }
+        Ok(config)
+    }
+}
+
+/// The name of the file in the output directory which records the tables
+/// that have been completely exported
+const MANIFEST: &str = ".sqlite2parquet-manifest.yaml";
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    tables: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ManifestEntry {
+    /// The number of rows written to the parquet file
+    n_rows: u64,
+    /// The state of the source at the time of the export
+    source: Source,
+}
+
+/// A fingerprint of the data which goes into a table's export
+#[derive(PartialEq, serde::Serialize, serde::Deserialize)]
+struct Source {
+    /// The table's `CREATE TABLE` statement
+    sql: Option<String>,
+    /// The sha3sum of the table's content, or of the results of the queries
+    /// in the config
+    sha3sum: String,
+    /// The sha3sums of the tables which map and denormalized columns are
+    /// taken from, by name
+    related: BTreeMap<String, String>,
+    /// The columns from the config, if there was one
+    config: Option<Vec<Column>>,
+}
+
+impl Source {
+    fn new(
+        conn: &Connection,
+        table: &str,
+        config: Option<&[Column]>,
+        related: &Related,
+    ) -> Result<Source> {
+        let sql = conn
+            .query_row(
+                "SELECT sql FROM sqlite_schema WHERE name = ?1",
+                [table],
+                |x| x.get(0),
+            )
+            .optional()?
+            .flatten();
+        let sha3sum = match config {
+            Some(config) => sha3sum_queries(conn, config)?,
+            None => sqlite2parquet::sha3sum_table(conn, table)?,
+        };
+        let related = related
+            .tables(conn, table)?
+            .into_iter()
+            .map(|x| {
+                let sha3sum = sqlite2parquet::sha3sum_table(conn, &x)?;
+                Ok((x, sha3sum))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Source {
+            sql,
+            sha3sum,
+            related,
+            config: config.map(|x| x.to_vec()),
+        })
+    }
+}
+
+/// Hashes the results of each column's query in turn
+fn sha3sum_queries(conn: &Connection, cols: &[Column]) -> Result<String> {
+    let mut hasher = Sha3Sum::new();
+    for col in cols {
+        hasher.table(&col.name);
+        let mut stmnt = conn.prepare(&col.query).context(col.query.clone())?;
+        let mut rows = stmnt.query([])?;
+        while let Some(row) = rows.next()? {
+            hasher.row([row.get_ref(0)?]);
+        }
+    }
+    Ok(hasher.finish())
+}
+
+impl Manifest {
+    /// Checks whether a previous run wrote the whole table to `out`, and the
+    /// table hasn't changed since
+    fn is_complete(&self, table: &str, source: &Source, out: &Path) -> bool {
+        let Some(entry) = self.tables.get(table) else {
+            return false;
+        };
+        let n_rows = std::fs::File::open(out).ok().and_then(|file| {
+            use parquet::file::reader::FileReader;
+            let rdr = parquet::file::reader::SerializedFileReader::new(file).ok()?;
+            Some(rdr.metadata().file_metadata().num_rows())
+        });
+        entry.source == *source && n_rows == Some(entry.n_rows as i64)
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let out = AtomicFile::create(path)?;
+        serde_yaml::to_writer(out.file(), self)?;
+        out.commit()
+    }
+}
+
+/// A file which is written under a temporary name, and moved into place
+/// once it's complete.  If it's dropped without being committed (eg. because
+/// of an error or a panic), the temporary file is removed.
+struct AtomicFile {
+    path: PathBuf,
+    tmp: PathBuf,
+    file: std::fs::File,
+    committed: bool,
+}
+
+impl AtomicFile {
+    fn create(path: &Path) -> Result<AtomicFile> {
+        let tmp = tmp_path(path);
+        let file = std::fs::File::create(&tmp)?;
+        Ok(AtomicFile {
+            path: path.to_owned(),
+            tmp,
+            file,
+            committed: false,
+        })
+    }
+
+    fn file(&self) -> &std::fs::File {
+        &self.file
+    }
+
+    /// Flushes the file to disk and moves it into place
+    fn commit(mut self) -> Result<()> {
+        self.file.sync_all()?;
+        std::fs::rename(&self.tmp, &self.path)?;
+        self.committed = true;
+        // Make sure the rename itself is durable
+        #[cfg(unix)]
+        if let Some(dir) = self.path.parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            std::fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+const TMP_SUFFIX: &str = ".tmp";
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(TMP_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Removes temporary files left behind by a previous run which was killed
+fn remove_stale_tmp_files(out_dir: &Path, rep: Reporter) -> Result<()> {
+    for entry in std::fs::read_dir(out_dir)? {
+        let path = entry?.path();
+        let stale = path.file_name().and_then(|x| x.to_str()).is_some_and(|x| {
+            x.strip_suffix(TMP_SUFFIX)
+                .is_some_and(|x| x.ends_with(".parquet") || x == MANIFEST)
+        });
+        if stale {
+            say!(rep, "Removing stale temporary file {}", path.display());
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn merge(opts: MergeOpts) -> Result<()> {
+    if opts.inputs.contains(&opts.output) {
+        anyhow::bail!("{} is one of the inputs", opts.output.display());
+    }
+    println!("Merging {} files...", opts.inputs.len());
+    let t_start = std::time::Instant::now();
+    let out = AtomicFile::create(&opts.output)?;
+    let metadata = sqlite2parquet::merge_files(&opts.inputs, out.file(), opts.group_size)?;
+    out.commit()?;
+    println!(
+        "Wrote {} rows as {} group{} to {} in {:.1?}",
+        metadata.num_rows,
+        metadata.row_groups.len(),
+        if metadata.row_groups.len() == 1 {
+            ""
+        } else {
+            "s"
+        },
+        opts.output.display(),
+        t_start.elapsed(),
+    );
+    Ok(())
+}
+
+/// Infers the schema for a table, printing the columns as we go
+fn infer_table(
+    conn: &Connection,
+    table: &str,
+    opts: &InferOptions,
+    rep: Reporter,
+) -> Result<Vec<Column>> {
+    say!(rep, "Inferring schema for {table}...");
+    say!(rep, "    {}", COLUMN_HEADER);
+    let t_start = std::time::Instant::now();
+    let cols = sqlite2parquet::infer_schema_with_options(conn, table, opts)?
+        .inspect(|col| {
+            if let Ok(col) = col {
+                say!(rep, "    {}", col)
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    say!(
+        rep,
+        "Inferred schema for {table} in {:.1?}",
+        t_start.elapsed()
+    );
+    Ok(cols)
+}
+
+fn infer(opts: InferOpts) -> Result<()> {
+    let conn = rusqlite::Connection::open(&opts.sqlite)?;
+    let tables = select_tables(&conn, opts.table, &BTreeMap::new(), opts.selection)?;
+    println!("Selected {} tables: {}", tables.len(), tables.join(", "));
+    let mut config = BTreeMap::<String, Vec<Column>>::new();
+    let infer_opts = opts.infer.options();
+    for table in tables {
+        let cols = infer_table(
+            &conn,
+            &table,
+            &infer_opts,
+            Reporter::new(OutputFormat::Text),
+        )?;
+        config.insert(table, cols);
+    }
+    if let Some(path) = opts.output {
+        let out = AtomicFile::create(&path)?;
+        serde_yaml::to_writer(out.file(), &config)?;
+        out.commit()?;
+        println!("Wrote schema to {}", path.display());
+    }
+    Ok(())
+}
+
+fn restore(opts: RestoreOpts) -> Result<()> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(&opts.out_dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|x| x.to_str()) else {
+            continue;
+        };
+        let Some(table) = name.strip_suffix(".parquet") else {
+            continue;
+        };
+        if table.contains(".delta-") {
+            anyhow::bail!(
+                "{}: Delta files must be compacted before restoring",
+                path.display()
+            );
+        }
+        if opts.table.is_empty() || opts.table.iter().any(|x| x == table) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    // If the schema was exported too, we can restore the original definitions
+    let schema_path = opts.out_dir.join("sqlite_schema.parquet");
+    let schema = if schema_path.exists() {
+        sqlite2parquet::read_sqlite_schema(&schema_path)?
     } else {
-        println!("Inferring schema for {table}...");
-        println!("    {}", COLUMN_HEADER);
+        vec![]
+    };
+    paths.retain(|x| *x != schema_path);
+
+    let conn = rusqlite::Connection::open(&opts.sqlite)?;
+    let mut restored = vec![];
+    for path in paths {
+        let table = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let create_sql = schema
+            .iter()
+            .find(|x| x.kind == "table" && x.name == table)
+            .and_then(|x| x.sql.as_deref());
+        print!("Restoring {table}...");
+        std::io::stdout().flush()?;
         let t_start = std::time::Instant::now();
-        let cols = sqlite2parquet::infer_schema(conn, table)?
-            .inspect(|col| {
-                if let Ok(col) = col {
-                    println!("    {}", col)
-                }
-            })
-            .collect::<Result<Vec<_>>>()?;
-        println!("Inferred schema in {:?}", t_start.elapsed());
-        cols
+        let n_rows = sqlite2parquet::restore_table(&conn, &path, create_sql)
+            .with_context(|| path.display().to_string())?;
+        println!(" {n_rows} rows in {:.1?}", t_start.elapsed());
+        restored.push(table);
+    }
+
+    // Indices and triggers are created after the data is loaded: it's faster,
+    // and we don't want the triggers to fire
+    for obj in &schema {
+        let Some(sql) = &obj.sql else { continue };
+        let wanted = match obj.kind.as_str() {
+            "index" | "trigger" => restored.contains(&obj.table),
+            "view" => opts.table.is_empty(),
+            _ => false,
+        };
+        if wanted {
+            println!("Creating {} {}", obj.kind, obj.name);
+            conn.execute_batch(sql).with_context(|| sql.clone())?;
+        }
+    }
+    Ok(())
+}
+
+fn verify(opts: VerifyOpts) -> Result<()> {
+    let Config {
+        selection,
+        maps,
+        denormalize,
+        tables: mut config,
+        ..
+    } = Config::load(opts.config.as_deref())?;
+    let related = Related {
+        maps,
+        denormalize,
+        all_foreign_keys: opts.denormalize,
+    };
+    let conn = rusqlite::Connection::open(&opts.sqlite)?;
+    let tables = select_tables(&conn, opts.table, &config, opts.selection.and(selection))?;
+    println!("Selected {} tables: {}", tables.len(), tables.join(", "));
+
+    let mut n_failed = 0;
+    for table in &tables {
+        let path = opts.out_dir.join(format!("{table}.parquet"));
+        println!("Verifying {table}...");
+        let t_start = std::time::Instant::now();
+        let extra = related.columns(&conn, table, &InferOptions::default())?;
+        let report = match config.remove(table) {
+            None if extra.is_empty() => verify_table(&conn, table, &path),
+            cols => {
+                let mut cols = match cols {
+                    Some(cols) => cols,
+                    None => {
+                        sqlite2parquet::infer_schema(&conn, table)?.collect::<Result<Vec<_>>>()?
+                    }
+                };
+                cols.extend(extra);
+                sqlite2parquet::verify(&conn, &cols, &path)
+            }
+        }
+        .with_context(|| path.display().to_string())?;
+        print!("{report}");
+        if report.is_ok() {
+            println!("{table}: ok ({:.1?})", t_start.elapsed());
+        } else {
+            println!("{table}: FAILED");
+            n_failed += 1;
+        }
+        println!();
+    }
+    if n_failed > 0 {
+        anyhow::bail!("{n_failed} of {} tables failed verification", tables.len());
+    }
+    Ok(())
+}
+
+fn inspect(opts: InspectOpts) -> Result<()> {
+    for path in &opts.files {
+        let info = sqlite2parquet::inspect(path).with_context(|| path.display().to_string())?;
+        if opts.json {
+            serde_json::to_writer(std::io::stdout(), &info)?;
+            println!();
+        } else {
+            print_file_info(path, &info);
+        }
+    }
+    Ok(())
+}
+
+fn print_file_info(path: &Path, info: &FileInfo) {
+    fn fmt_stat(x: &Option<StatValue>) -> String {
+        x.as_ref().map_or("-".into(), |x| x.to_string())
+    }
+
+    println!(
+        "{}: table {}, {} rows in {} group{}",
+        path.display(),
+        info.name,
+        info.n_rows,
+        info.row_groups.len(),
+        if info.row_groups.len() == 1 { "" } else { "s" },
+    );
+    println!();
+    println!("    {}", COLUMN_HEADER);
+    for col in &info.columns {
+        match &col.unsupported_type {
+            None => println!("    {}", col.column),
+            Some(x) => println!("    {} (stored as {x})", col.column),
+        }
+    }
+    println!();
+    println!(
+        "    {:20} {:>13} {:>13} {:>6} {:>10}  Encodings",
+        "Column", "Compressed", "Uncompressed", "Ratio", "Nulls"
+    );
+    for col in &info.columns {
+        println!(
+            "    {:20} {} {} {:>5.1}x {:>10}  {}",
+            col.column.name,
+            fmt_bytes(col.stats.compressed_bytes),
+            fmt_bytes(col.stats.uncompressed_bytes),
+            col.stats.uncompressed_bytes as f64 / col.stats.compressed_bytes as f64,
+            col.stats
+                .null_count
+                .map_or("-".to_string(), |x| x.to_string()),
+            col.encodings.join(", "),
+        );
+    }
+    println!();
+    println!("    {:20} {:>20} {:>20}", "Column", "Min", "Max");
+    for col in &info.columns {
+        println!(
+            "    {:20} {:>20} {:>20}",
+            col.column.name,
+            fmt_stat(&col.stats.min),
+            fmt_stat(&col.stats.max),
+        );
+    }
+    println!();
+    println!(
+        "    {:>5} {:>12} {:>13} {:>13}",
+        "Group", "Rows", "Compressed", "Uncompressed"
+    );
+    for (i, group) in info.row_groups.iter().enumerate() {
+        println!(
+            "    {:>5} {:>12} {} {}",
+            i,
+            group.n_rows,
+            fmt_bytes(group.compressed_bytes),
+            fmt_bytes(group.uncompressed_bytes),
+        );
+    }
+    if !info.key_value_metadata.is_empty() {
+        println!();
+        for (key, value) in &info.key_value_metadata {
+            println!("    {key}: {}", value.as_deref().unwrap_or(""));
+        }
+    }
+    println!();
+}
+
+const COLUMN_HEADER: &str =
+    "Column                 Physical type   Encoding             Logical type               SQL";
+
+/// Exports the results of a query, inferring the schema from the results
+fn mk_query(
+    conn: &Connection,
+    name: &str,
+    sql: &str,
+    outpath: &Path,
+    settings: &Settings,
+    rep: Reporter,
+) -> Result<u64> {
+    say!(rep, "Exporting query {name}");
+    say!(rep, "    {}", COLUMN_HEADER);
+    let t_start = std::time::Instant::now();
+    let cols = sqlite2parquet::infer_query_schema_with_options(conn, sql, &settings.infer)?
+        .collect::<Result<Vec<_>>>()?;
+    for col in &cols {
+        say!(rep, "    {}", col);
+    }
+    let infer_time = t_start.elapsed();
+    say!(rep, "Inferred schema for {name} in {:.1?}", infer_time);
+
+    let out = AtomicFile::create(outpath)?;
+    let t_start = std::time::Instant::now();
+    let opts = WriteOptions {
+        key_value_metadata: vec![],
+        created_by: settings.created_by.clone(),
+    };
+    let metadata = sqlite2parquet::write_query(
+        conn,
+        name,
+        sql,
+        &cols,
+        &opts,
+        out.file(),
+        settings.group_size,
+    )
+    .with_context(|| format!("Query {name}"))?;
+    out.commit()?;
+    let write_time = t_start.elapsed();
+
+    let n_rows = metadata.num_rows as u64;
+    let (total_bytes, by_col_bytes) = column_sizes(&cols, &metadata);
+    if rep.format == OutputFormat::Json {
+        rep.event(serde_json::json!({
+            "event": "query",
+            "name": name,
+            "sql": sql,
+            "path": outpath,
+            "rows": n_rows,
+            "groups": metadata.row_groups.len(),
+            "bytes": total_bytes,
+            "columns": cols.iter().zip(&by_col_bytes).map(|(col, bytes)| {
+                serde_json::json!({ "name": col.name, "bytes": bytes })
+            }).collect::<Vec<_>>(),
+            "schema": cols,
+            "timings": {
+                "infer_secs": infer_time.as_secs_f64(),
+                "write_secs": write_time.as_secs_f64(),
+            },
+        }))?;
+    } else {
+        println!(
+            "Wrote {n_rows} rows to {} in {:.1?}",
+            outpath.display(),
+            write_time
+        );
+        summarize(&cols, total_bytes, &by_col_bytes);
+    }
+    Ok(n_rows)
+}
+
+/// Settings which are the same for every table in an export
+struct Settings {
+    group_size: usize,
+    infer: InferOptions,
+    /// Overrides the parquet-rs version in the file metadata
+    created_by: Option<String>,
+    related: Related,
+}
+
+/// Exports a table.  If it's exported as-is (ie. the columns are inferred,
+/// booleans aren't parsed leniently, and there are no extra columns from
+/// other tables), its sha3sum is recorded in the file metadata.
+#[allow(clippy::too_many_arguments)]
+fn mk_table(
+    conn: &Connection,
+    table: &str,
+    outpath: &Path,
+    // Infer if `None`
+    config: Option<Vec<Column>>,
+    // The table's sha3sum, if we already know it
+    sha3sum: Option<String>,
+    settings: &Settings,
+    rep: Reporter,
+    overall: &Overall,
+) -> Result<u64> {
+    say!(
+        rep,
+        "Exporting {table} (table {} of {})",
+        overall.tables_done + 1,
+        overall.n_tables,
+    );
+    let n_rows = overall.table_rows;
+
+    let t_start = std::time::Instant::now();
+    let (mut cols, inferred): (Vec<Column>, bool) = if let Some(cols) = config {
+        say!(rep, "    {}", COLUMN_HEADER);
+        for col in &cols {
+            say!(rep, "    {}", col);
+        }
+        (cols, false)
+    } else {
+        (infer_table(conn, table, &settings.infer, rep)?, true)
     };
+    let related = settings.related.columns(conn, table, &settings.infer)?;
+    for col in &related {
+        say!(rep, "    {}", col);
+    }
+    // The sha3sum is only meaningful if the file has the same content as the
+    // table, which it doesn't if booleans were parsed from text
+    let plain = inferred && related.is_empty() && !settings.infer.lenient_bools;
+    cols.extend(related);
+    let infer_time = t_start.elapsed();
 
+    let group_size = settings.group_size;
     let total = Progress {
-        n_cols: cols.len() as u64,
+        n_cols: cols.iter().map(|x| x.n_leaves() as u64).sum(),
         n_rows,
-        n_groups: (n_rows + group_size as u64 - 1) / group_size as u64,
+        n_groups: n_rows.div_ceil(group_size as u64),
     };
 
-    let group_size = group_size.max(1);
-    println!("Group size: {}", group_size);
+    say!(rep, "Group size: {}", group_size);
 
-    let outfile = std::fs::File::create(outpath)?;
+    let t_start = std::time::Instant::now();
+    let sha3sum = match sha3sum {
+        _ if !plain => None,
+        Some(x) => Some(x),
+        None => {
+            if rep.format == OutputFormat::Text {
+                print!("Hashing {table}...");
+                std::io::stdout().flush()?;
+            }
+            let sha3sum = sqlite2parquet::sha3sum_table(conn, table)?;
+            say!(rep, " {sha3sum} ({:.1?})", t_start.elapsed());
+            Some(sha3sum)
+        }
+    };
+    let hash_time = t_start.elapsed();
+    let kv = sha3sum
+        .iter()
+        .map(|x| parquet::format::KeyValue::new(SHA3SUM_KEY.to_string(), x.clone()))
+        .collect();
+
+    let out = AtomicFile::create(outpath)?;
 
     let t_start = std::time::Instant::now();
-    let metadata = sqlite2parquet::write_table_with_progress(
+    let opts = WriteOptions {
+        key_value_metadata: kv,
+        created_by: settings.created_by.clone(),
+    };
+    let metadata = sqlite2parquet::write_table_with_options(
         conn,
         table,
         &cols,
-        outfile,
+        &opts,
+        out.file(),
         group_size,
-        |written| print_progress(written, total, group_size, t_start.elapsed(), false),
+        |written| {
+            let time = t_start.elapsed();
+            rep.progress(table, written, total, group_size, time, overall, false)
+        },
     )?;
+    out.commit()?;
+    let write_time = t_start.elapsed();
     let final_prog = Progress {
         n_cols: total.n_cols,
         n_rows: metadata.num_rows as u64,
         n_groups: metadata.row_groups.len() as u64,
     };
-    print_progress(final_prog, final_prog, group_size, t_start.elapsed(), true)?;
+    rep.progress(
+        table, final_prog, final_prog, group_size, write_time, overall, true,
+    )?;
+
+    let n_rows = metadata.num_rows as u64;
+    let (total_bytes, by_col_bytes) = column_sizes(&cols, &metadata);
+    if rep.format == OutputFormat::Json {
+        rep.event(serde_json::json!({
+            "event": "table",
+            "table": table,
+            "path": outpath,
+            "rows": n_rows,
+            "groups": metadata.row_groups.len(),
+            "bytes": total_bytes,
+            "columns": cols.iter().zip(&by_col_bytes).map(|(col, bytes)| {
+                serde_json::json!({ "name": col.name, "bytes": bytes })
+            }).collect::<Vec<_>>(),
+            "schema": cols,
+            "inferred": inferred,
+            "sha3sum": sha3sum,
+            "timings": {
+                "infer_secs": infer_time.as_secs_f64(),
+                "hash_secs": hash_time.as_secs_f64(),
+                "write_secs": write_time.as_secs_f64(),
+            },
+        }))?;
+    } else {
+        summarize(&cols, total_bytes, &by_col_bytes);
+    }
+    Ok(n_rows)
+}
 
-    summarize(&cols, metadata);
+/// Finds the existing delta files for this table, oldest first
+#[cfg(feature = "cdc")]
+fn delta_paths(out_dir: &Path, table: &str) -> Result<Vec<(u32, PathBuf)>> {
+    let prefix = format!("{table}.delta-");
+    let mut deltas = vec![];
+    for entry in std::fs::read_dir(out_dir)? {
+        let path = entry?.path();
+        let n = path.file_name().and_then(|x| x.to_str()).and_then(|x| {
+            x.strip_prefix(&prefix)?
+                .strip_suffix(".parquet")?
+                .parse()
+                .ok()
+        });
+        if let Some(n) = n {
+            deltas.push((n, path));
+        }
+    }
+    deltas.sort();
+    Ok(deltas)
+}
+
+#[cfg(feature = "cdc")]
+fn mk_delta(
+    conn: &Connection,
+    table: &str,
+    snapshot: &Path,
+    out_dir: &Path,
+    group_size: usize,
+    rep: Reporter,
+) -> Result<()> {
+    let deltas = delta_paths(out_dir, table)?;
+    let n = deltas.last().map_or(1, |(n, _)| n + 1);
+    let outpath = out_dir.join(format!("{table}.delta-{n:04}.parquet"));
+    let deltas = deltas.into_iter().map(|(_, x)| x).collect::<Vec<_>>();
+    say!(
+        rep,
+        "Diffing {table} against {} + {} deltas...",
+        snapshot.display(),
+        deltas.len()
+    );
+    let out = AtomicFile::create(&outpath)?;
+    let t_start = std::time::Instant::now();
+    let metadata = sqlite2parquet::cdc::write_delta(
+        conn,
+        table,
+        snapshot,
+        &deltas,
+        out.file(),
+        group_size.max(1),
+    )?;
+    match metadata {
+        Some(metadata) => {
+            out.commit()?;
+            say!(
+                rep,
+                "Wrote {} changed rows to {} in {:.1?}",
+                metadata.num_rows,
+                outpath.display(),
+                t_start.elapsed(),
+            )
+        }
+        // Dropping `out` removes the empty file
+        None => say!(rep, "No changes"),
+    }
     Ok(())
 }
 
+#[cfg(feature = "cdc")]
+fn compact_table(
+    table: &str,
+    snapshot: &Path,
+    out_dir: &Path,
+    group_size: usize,
+    rep: Reporter,
+) -> Result<()> {
+    let deltas = delta_paths(out_dir, table)?
+        .into_iter()
+        .map(|(_, x)| x)
+        .collect::<Vec<_>>();
+    if deltas.is_empty() {
+        say!(rep, "No deltas for {table}");
+        return Ok(());
+    }
+    say!(
+        rep,
+        "Compacting {} deltas into {}...",
+        deltas.len(),
+        snapshot.display()
+    );
+    let out = AtomicFile::create(snapshot)?;
+    let metadata = sqlite2parquet::cdc::compact(snapshot, &deltas, out.file(), group_size.max(1))?;
+    out.commit()?;
+    for path in deltas {
+        std::fs::remove_file(path)?;
+    }
+    say!(rep, "Wrote {} rows", metadata.num_rows);
+    Ok(())
+}
+
+/// Decides how to report what we're doing
+#[derive(Clone, Copy)]
+struct Reporter {
+    format: OutputFormat,
+    /// If stderr isn't a terminal, progress is printed as plain lines
+    tty: bool,
+}
+
+impl Reporter {
+    fn new(format: OutputFormat) -> Reporter {
+        use std::io::IsTerminal;
+        Reporter {
+            format,
+            tty: std::io::stderr().is_terminal(),
+        }
+    }
+
+    /// Prints a line of JSON to stdout, if the output format is JSON
+    fn event(&self, event: serde_json::Value) -> Result<()> {
+        if self.format == OutputFormat::Json {
+            let mut out = std::io::stdout().lock();
+            serde_json::to_writer(&mut out, &event)?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn progress(
+        &self,
+        table: &str,
+        written: Progress,
+        total: Progress,
+        group_size: usize,
+        time: std::time::Duration,
+        overall: &Overall,
+        finished: bool,
+    ) -> Result<()> {
+        // Unless we're redrawing a progress bar, once per group is plenty
+        if !finished && written.n_cols != 0 && !(self.tty && self.format == OutputFormat::Text) {
+            return Ok(());
+        }
+        let stats = overall.stats(written.n_rows);
+        match self.format {
+            OutputFormat::Json => self.event(serde_json::json!({
+                "event": "progress",
+                "table": table,
+                "rows_written": written.n_rows,
+                "rows_total": total.n_rows,
+                "rows_total_exact": overall.table_rows_exact,
+                "groups_written": written.n_groups,
+                "groups_total": total.n_groups,
+                "elapsed_secs": time.as_secs_f64(),
+                "rows_per_sec": written.n_rows as f64 / time.as_secs_f64(),
+                "finished": finished,
+                "overall": {
+                    "tables_done": overall.tables_done,
+                    "tables_total": overall.n_tables,
+                    "rows_written": stats.rows,
+                    "rows_total": overall.rows_total,
+                    "elapsed_secs": overall.t_start.elapsed().as_secs_f64(),
+                    "rows_per_sec": stats.rate,
+                    "eta_secs": stats.eta.map(|x| x.as_secs_f64()),
+                },
+            })),
+            OutputFormat::Text => {
+                let total_msg = format!(
+                    "{}{}",
+                    if overall.table_rows_exact { "" } else { "~" },
+                    total.n_rows,
+                );
+                let overall_msg = format!(
+                    "Overall: [{:.2}%] table {} of {}, {:.0} rows/s, ETA {}",
+                    stats.pc,
+                    overall.tables_done + 1,
+                    overall.n_tables,
+                    stats.rate,
+                    stats.eta.map_or("?".into(), fmt_eta),
+                );
+                print_progress(
+                    written,
+                    total,
+                    &total_msg,
+                    group_size,
+                    time,
+                    &overall_msg,
+                    finished,
+                    self.tty,
+                )
+            }
+        }
+    }
+}
+
+fn fmt_eta(x: std::time::Duration) -> String {
+    let secs = x.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs / 60 % 60),
+    }
+}
+
+/// Prints the progress of the current table.  On a terminal, the overall
+/// progress is shown on a second line underneath, which is cleared once the
+/// table is finished.
+#[allow(clippy::too_many_arguments)]
 fn print_progress(
     written: Progress,
     total: Progress,
+    total_msg: &str,
     group_size: usize,
     time: std::time::Duration,
+    overall: &str,
     finished: bool,
+    tty: bool,
 ) -> Result<()> {
     use crossterm::*;
     let out = std::io::stderr();
     let mut out = out.lock();
-    let this_group = (total.n_rows - written.n_rows).min(group_size as u64) as f64;
+    // The total may be an estimate, so we could write more rows than expected
+    let this_group = total
+        .n_rows
+        .saturating_sub(written.n_rows)
+        .min(group_size as u64) as f64;
     let pc = (written.n_rows as f64 + this_group * written.n_cols as f64 / total.n_cols as f64)
-        / total.n_rows as f64
+        / total.n_rows.max(1) as f64
         * 100.0;
-    out.queue(cursor::MoveToColumn(1))?
-        .queue(terminal::Clear(terminal::ClearType::CurrentLine))?
-        .queue(style::Print(format_args!(
-            "[{:.2}%] Wrote {}{} rows as {} group{} in {:.1?}{}",
-            pc,
-            written.n_rows,
-            if finished {
-                "".to_string()
-            } else {
-                format!(" of {} ", total.n_rows)
-            },
-            written.n_groups,
-            if written.n_groups == 1 { "" } else { "s" },
-            time,
-            if finished { "\n" } else { "..." },
-        )))?
-        .flush()?;
+    let pc = if finished { 100.0 } else { pc.min(99.99) };
+    let msg = format!(
+        "[{:.2}%] Wrote {}{} rows as {} group{} in {:.1?}",
+        pc,
+        written.n_rows,
+        if finished {
+            "".to_string()
+        } else {
+            format!(" of {total_msg}")
+        },
+        written.n_groups,
+        if written.n_groups == 1 { "" } else { "s" },
+        time,
+    );
+    if !tty {
+        writeln!(out, "{msg} ({overall})")?;
+        return Ok(());
+    }
+    out.queue(cursor::MoveToColumn(0))?
+        .queue(terminal::Clear(terminal::ClearType::FromCursorDown))?;
+    if finished {
+        out.queue(style::Print(format_args!("{msg}\n")))?;
+    } else {
+        out.queue(style::Print(format_args!("{msg}...\n{overall}")))?
+            .queue(cursor::MoveToPreviousLine(1))?;
+    }
+    out.flush()?;
     Ok(())
 }
 
-fn summarize(cols: &[Column], metadata: parquet::format::FileMetaData) {
-    fn fmt_bytes(bytes: i64) -> String {
-        use thousands::Separable;
-        format!("{:>9} KiB", (bytes / 1024).separate_with_commas())
-    }
+fn fmt_bytes(bytes: i64) -> String {
+    use thousands::Separable;
+    format!("{:>9} KiB", (bytes / 1024).separate_with_commas())
+}
 
+/// The total size of the data, and the compressed size of each column
+fn column_sizes(cols: &[Column], metadata: &parquet::format::FileMetaData) -> (i64, Vec<i64>) {
     let mut total_bytes = 0;
     let mut by_col_bytes = cols.iter().map(|_| 0).collect::<Vec<_>>();
+    // Maps are written as two leaf columns
+    let leaves = cols
+        .iter()
+        .enumerate()
+        .flat_map(|(i, col)| std::iter::repeat_n(i, col.n_leaves()))
+        .collect::<Vec<_>>();
     for group in &metadata.row_groups {
         total_bytes += group.total_byte_size;
-        for (meta, col_bytes) in group.columns.iter().zip(&mut by_col_bytes) {
+        for (meta, i) in group.columns.iter().zip(&leaves) {
             if let Some(meta) = &meta.meta_data {
-                *col_bytes += meta.total_compressed_size;
+                by_col_bytes[*i] += meta.total_compressed_size;
             }
         }
     }
+    (total_bytes, by_col_bytes)
+}
+
+fn summarize(cols: &[Column], total_bytes: i64, by_col_bytes: &[i64]) {
     println!("Total                  {}", fmt_bytes(total_bytes));
     for (col, col_bytes) in cols.iter().zip(by_col_bytes) {
         println!(
             "  {:20} {} ({:>2.0}%)",
             col.name,
-            fmt_bytes(col_bytes),
-            col_bytes as f64 / total_bytes as f64 * 100.0,
+            fmt_bytes(*col_bytes),
+            *col_bytes as f64 / total_bytes as f64 * 100.0,
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE a (x INTEGER); INSERT INTO a VALUES (1), (2);")
+            .unwrap();
+        let out = dir.path().join("a.parquet");
+        let cols = infer_schema(&conn, "a")
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        write_table(&conn, "a", &cols, std::fs::File::create(&out).unwrap(), 10).unwrap();
+
+        let related = Related {
+            maps: Maps::new(),
+            denormalize: BTreeMap::new(),
+            all_foreign_keys: false,
+        };
+        let source = || Source::new(&conn, "a", None, &related).unwrap();
+        let mut manifest = Manifest::default();
+        assert!(!manifest.is_complete("a", &source(), &out));
+        let entry = ManifestEntry {
+            n_rows: 2,
+            source: source(),
+        };
+        manifest.tables.insert("a".into(), entry);
+        assert!(manifest.is_complete("a", &source(), &out));
+
+        // The manifest survives a round-trip through the file
+        let path = dir.path().join(MANIFEST);
+        manifest.save(&path).unwrap();
+        let manifest: Manifest =
+            serde_yaml::from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+        assert!(manifest.is_complete("a", &source(), &out));
+
+        // Changing the content, or removing the file, means it's not done
+        conn.execute("UPDATE a SET x = 3 WHERE x = 2", []).unwrap();
+        assert!(!manifest.is_complete("a", &source(), &out));
+        conn.execute("UPDATE a SET x = 2 WHERE x = 3", []).unwrap();
+        std::fs::remove_file(&out).unwrap();
+        assert!(!manifest.is_complete("a", &source(), &out));
+    }
+}
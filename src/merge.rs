@@ -0,0 +1,286 @@
+use crate::read::ParquetFile;
+use crate::{
+    mk_writer, write_group_from_rows, Column, LogicalType, PhysicalType, Result, WriteOptions,
+};
+use anyhow::{bail, Context};
+use std::io::Write;
+use std::path::Path;
+
+/// Merges several parquet files into one, without going back to sqlite.
+///
+/// This is useful for tidying up after incremental or partitioned exports,
+/// which tend to leave lots of small files behind.  The files must have
+/// been written by sqlite2parquet and share a schema: the same column names,
+/// physical types, and logical types, in the same order.  If a column is
+/// required in some files but not others, it will be optional in the output.
+/// Integer columns are the exception: if their widths or signedness differ,
+/// the output uses a type wide enough for all of them.
+///
+/// The rows are written in order, re-split into row groups of length
+/// `group_size` (see [`write_table()`](crate::write_table())).  Encodings
+/// are taken from the first file.
+///
+/// The key-value metadata (eg. the values of enum columns) must be the same
+/// in every file, and is copied to the output; so is `created_by`, if the
+/// files agree on it.  The exception is the checksum recorded under
+/// [`SHA3SUM_KEY`](crate::SHA3SUM_KEY), which describes the content of each
+/// file and so is left out.
+pub fn merge_files<P: AsRef<Path>>(
+    inputs: &[P],
+    out: impl Write + Send,
+    group_size: usize,
+) -> Result<parquet::format::FileMetaData> {
+    let files = inputs
+        .iter()
+        .map(|path| {
+            let path = path.as_ref();
+            ParquetFile::open(path).with_context(|| path.display().to_string())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let Some(first) = files.first() else {
+        bail!("No files to merge");
+    };
+    let table_name = first.name.clone();
+    let mut cols = first.cols.clone();
+    for (file, path) in files.iter().zip(inputs).skip(1) {
+        let path = path.as_ref().display();
+        if file.cols.len() != cols.len() {
+            bail!(
+                "{path}: Expected {} columns, found {}",
+                cols.len(),
+                file.cols.len()
+            );
+        }
+        for (col, x) in cols.iter_mut().zip(&file.cols) {
+            let differ = (x.physical_type, x.logical_type) != (col.physical_type, col.logical_type);
+            let widened = (x.name == col.name && differ)
+                .then(|| widen_integers(col, x))
+                .flatten();
+            if let Some((physical_type, logical_type)) = widened {
+                col.physical_type = physical_type;
+                col.logical_type = Some(logical_type);
+            } else if (&x.name, x.physical_type, x.logical_type)
+                != (&col.name, col.physical_type, col.logical_type)
+            {
+                bail!(
+                    "{path}: Expected column {} ({}, {:?}), found {} ({}, {:?})",
+                    col.name,
+                    col.physical_type,
+                    col.logical_type,
+                    x.name,
+                    x.physical_type,
+                    x.logical_type,
+                );
+            }
+            if x.values != col.values {
+                bail!(
+                    "{path}: The values of the enum column {} are different",
+                    col.name
+                );
+            }
+            col.required &= x.required;
+        }
+        if key_value_metadata(file) != key_value_metadata(first) {
+            bail!("{path}: The key-value metadata is different");
+        }
+    }
+    let created_by = first.metadata().file_metadata().created_by();
+    let opts = WriteOptions {
+        key_value_metadata: key_value_metadata(first),
+        created_by: files
+            .iter()
+            .all(|x| x.metadata().file_metadata().created_by() == created_by)
+            .then(|| created_by.map(String::from))
+            .flatten(),
+    };
+
+    let group_size = group_size.max(1);
+    let mut wtr = mk_writer(&table_name, &cols, &opts, out)?;
+    let leaves = crate::schema::leaf_columns(&cols);
+    let mut buf = Vec::with_capacity(group_size);
+    let mut n_groups = 0;
+    for (file, path) in files.into_iter().zip(inputs) {
+        for row in file.rows() {
+            buf.push(row.with_context(|| path.as_ref().display().to_string())?);
+            if buf.len() == group_size {
+                write_group_from_rows(&mut wtr, &buf, &leaves)
+                    .context(format!("Group {n_groups}"))?;
+                buf.clear();
+                n_groups += 1;
+            }
+        }
+    }
+    if !buf.is_empty() {
+        write_group_from_rows(&mut wtr, &buf, &leaves).context(format!("Group {n_groups}"))?;
+    }
+    Ok(wtr.close()?)
+}
+
+/// The key-value metadata which should be carried over to a merged file.
+/// The enum values are left out, since they're written from the columns.
+fn key_value_metadata(file: &ParquetFile) -> Vec<parquet::format::KeyValue> {
+    file.key_value_metadata()
+        .iter()
+        .filter(|kv| kv.key != crate::SHA3SUM_KEY)
+        .filter(|kv| !kv.key.starts_with(crate::schema::ENUM_VALUES_KEY))
+        .cloned()
+        .collect()
+}
+
+/// The narrowest integer type which can hold the values of both columns, if
+/// they're both integers.  Columns without an annotation are signed.
+fn widen_integers(x: &Column, y: &Column) -> Option<(PhysicalType, LogicalType)> {
+    let integer = |col: &Column| match (col.physical_type, col.logical_type) {
+        (
+            _,
+            Some(LogicalType::Integer {
+                bit_width,
+                is_signed,
+            }),
+        ) => Some((bit_width, is_signed)),
+        (PhysicalType::Int32, None) => Some((32, true)),
+        (PhysicalType::Int64, None) => Some((64, true)),
+        _ => None,
+    };
+    let (x, y) = (integer(x)?, integer(y)?);
+    let is_signed = x.1 || y.1;
+    // An unsigned type needs twice the width to be held in a signed one
+    let width = |(bits, signed): (i8, bool)| match signed || !is_signed {
+        true => bits,
+        false => bits.saturating_mul(2).min(64),
+    };
+    let bit_width = width(x).max(width(y));
+    let physical_type = match bit_width {
+        64 => PhysicalType::Int64,
+        _ => PhysicalType::Int32,
+    };
+    Some((
+        physical_type,
+        LogicalType::Integer {
+            bit_width,
+            is_signed,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read::ParquetFile;
+    use parquet::format::KeyValue;
+
+    /// Exports `table` to `path`, with the given metadata
+    fn export(conn: &rusqlite::Connection, table: &str, path: &Path, opts: &WriteOptions) {
+        let cols = crate::infer_schema(conn, table)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let out = std::fs::File::create(path).unwrap();
+        crate::write_table_with_options(conn, table, &cols, opts, &out, 1000, |_| Ok(())).unwrap();
+    }
+
+    fn rows(path: &Path) -> Vec<Vec<rusqlite::types::Value>> {
+        ParquetFile::open(path)
+            .unwrap()
+            .rows()
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn merge_keeps_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE a (id UUID, x TEXT CHECK (x IN ('foo', 'bar')));
+            INSERT INTO a VALUES ('0b9e8d1c-52e1-4c1f-8a3b-9c4d5e6f7a8b', 'foo');
+            CREATE TABLE b (id UUID, x TEXT CHECK (x IN ('foo', 'bar')));
+            INSERT INTO b VALUES (x'00112233445566778899aabbccddeeff', 'bar');",
+        )
+        .unwrap();
+        let opts = WriteOptions {
+            key_value_metadata: vec![
+                KeyValue::new("origin".to_string(), "test".to_string()),
+                KeyValue::new(crate::SHA3SUM_KEY.to_string(), "abc".to_string()),
+            ],
+            created_by: Some("sqlite2parquet".into()),
+        };
+        let (a, b) = (dir.path().join("a.parquet"), dir.path().join("b.parquet"));
+        export(&conn, "a", &a, &opts);
+        let opts = WriteOptions {
+            key_value_metadata: vec![
+                KeyValue::new("origin".to_string(), "test".to_string()),
+                KeyValue::new(crate::SHA3SUM_KEY.to_string(), "def".to_string()),
+            ],
+            ..opts
+        };
+        export(&conn, "b", &b, &opts);
+
+        let out_path = dir.path().join("merged.parquet");
+        let out = std::fs::File::create(&out_path).unwrap();
+        merge_files(&[&a, &b], &out, 1000).unwrap();
+
+        let merged = ParquetFile::open(&out_path).unwrap();
+        assert_eq!(merged.cols[1].values, ["foo", "bar"]);
+        let meta = merged.metadata().file_metadata();
+        assert_eq!(meta.created_by(), Some("sqlite2parquet"));
+        let keys = merged
+            .key_value_metadata()
+            .iter()
+            .map(|x| x.key.as_str())
+            .collect::<Vec<_>>();
+        assert!(keys.contains(&"origin"));
+        assert!(!keys.contains(&crate::SHA3SUM_KEY));
+
+        let mut expected = rows(&a);
+        expected.extend(rows(&b));
+        assert_eq!(rows(&out_path), expected);
+        assert_eq!(
+            expected[0][0],
+            rusqlite::types::Value::Blob(vec![
+                0x0b, 0x9e, 0x8d, 0x1c, 0x52, 0xe1, 0x4c, 0x1f, 0x8a, 0x3b, 0x9c, 0x4d, 0x5e, 0x6f,
+                0x7a, 0x8b
+            ]),
+        );
+    }
+
+    #[test]
+    fn merge_rejects_different_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE a (x INTEGER); INSERT INTO a VALUES (1);")
+            .unwrap();
+        let (a, b) = (dir.path().join("a.parquet"), dir.path().join("b.parquet"));
+        for (path, origin) in [(&a, "one"), (&b, "two")] {
+            let opts = WriteOptions {
+                key_value_metadata: vec![KeyValue::new("origin".to_string(), origin.to_string())],
+                created_by: None,
+            };
+            export(&conn, "a", path, &opts);
+        }
+        let err = merge_files(&[&a, &b], std::io::sink(), 1000).unwrap_err();
+        assert!(err.to_string().contains("key-value metadata"), "{err}");
+    }
+
+    #[test]
+    fn merge_widens_integers() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE a (x INTEGER); INSERT INTO a VALUES (1);
+            CREATE TABLE b (x INTEGER); INSERT INTO b VALUES (-100000);",
+        )
+        .unwrap();
+        let (a, b) = (dir.path().join("a.parquet"), dir.path().join("b.parquet"));
+        export(&conn, "a", &a, &WriteOptions::default());
+        export(&conn, "b", &b, &WriteOptions::default());
+        let out_path = dir.path().join("merged.parquet");
+        merge_files(&[&a, &b], std::fs::File::create(&out_path).unwrap(), 1000).unwrap();
+        let merged = ParquetFile::open(&out_path).unwrap();
+        assert_eq!(merged.cols[0].physical_type, PhysicalType::Int32);
+        assert_eq!(
+            rows(&out_path),
+            [[1.into()], [(-100000).into()]].map(Vec::from)
+        );
+    }
+}
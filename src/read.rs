@@ -0,0 +1,161 @@
+use crate::conversion::{field_to_json, field_to_value};
+use crate::{Column, Result};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::{Field, Row};
+use parquet::schema::types::TypePtr;
+use rusqlite::types::Value;
+use std::fs::File;
+use std::path::Path;
+
+/// A parquet file which was previously written by sqlite2parquet.
+pub(crate) struct ParquetFile {
+    /// The name of the table, as stored in the parquet schema
+    pub name: String,
+    /// The columns, recovered from the parquet schema.  The queries are empty.
+    pub cols: Vec<Column>,
+    reader: SerializedFileReader<File>,
+}
+
+impl ParquetFile {
+    pub fn open(path: &Path) -> Result<ParquetFile> {
+        let (file, unsupported) = ParquetFile::open_lenient(path)?;
+        for (col, x) in file.cols.iter().zip(unsupported) {
+            if let Some(x) = x {
+                anyhow::bail!("{}: Unsupported type: {x}", col.name);
+            }
+        }
+        Ok(file)
+    }
+
+    /// Opens any parquet file, even if some of its columns have types we
+    /// don't support.  Those columns are approximated (see
+    /// [`Column::from_parquet()`]), and the types from the file are returned
+    /// alongside.
+    pub fn open_lenient(path: &Path) -> Result<(ParquetFile, Vec<Option<String>>)> {
+        let reader = SerializedFileReader::new(File::open(path)?)?;
+        let meta = reader.metadata();
+        let schema = meta.file_metadata().schema_descr();
+        let first_group = (meta.num_row_groups() > 0).then(|| meta.row_group(0));
+        // The keys of a map are described by the column for its values
+        let is_map_key = |descr: &parquet::schema::types::ColumnDescriptor| {
+            let parts = descr.path().parts();
+            descr.max_rep_level() > 0 && parts.ends_with(&["key_value".into(), "key".into()])
+        };
+        let cols = schema
+            .columns()
+            .iter()
+            .enumerate()
+            .filter(|(_, descr)| !is_map_key(descr))
+            .map(|(i, descr)| Column::from_parquet(descr, first_group.map(|x| x.column(i))))
+            .collect::<Vec<_>>();
+        let (mut cols, unsupported): (Vec<Column>, Vec<Option<String>>) = cols.into_iter().unzip();
+        for kv in meta
+            .file_metadata()
+            .key_value_metadata()
+            .into_iter()
+            .flatten()
+        {
+            let Some(name) = kv.key.strip_prefix(crate::schema::ENUM_VALUES_KEY) else {
+                continue;
+            };
+            let name = name.strip_prefix('.').unwrap_or(name);
+            if let Some(col) = cols.iter_mut().find(|x| x.name == name) {
+                col.values = serde_json::from_str(kv.value.as_deref().unwrap_or("[]"))?;
+            }
+        }
+        let file = ParquetFile {
+            name: schema.name().to_string(),
+            cols,
+            reader,
+        };
+        Ok((file, unsupported))
+    }
+
+    pub fn metadata(&self) -> &parquet::file::metadata::ParquetMetaData {
+        self.reader.metadata()
+    }
+
+    /// The file's key-value metadata
+    pub fn key_value_metadata(&self) -> &[parquet::format::KeyValue] {
+        self.reader
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .map_or(&[], |x| x.as_slice())
+    }
+
+    /// Look up an entry in the file's key-value metadata
+    #[cfg(feature = "cdc")]
+    pub fn key_value(&self, key: &str) -> Option<&str> {
+        self.reader
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()?
+            .iter()
+            .find(|kv| kv.key == key)?
+            .value
+            .as_deref()
+    }
+
+    /// Iterate over the rows, converted back to sqlite values
+    pub fn rows(self) -> impl Iterator<Item = Result<Vec<Value>>> {
+        let fields = self
+            .metadata()
+            .file_metadata()
+            .schema()
+            .get_fields()
+            .to_vec();
+        self.reader.into_iter().map(move |row| {
+            let mut vals = vec![];
+            push_values(&row?, &fields, &mut vals)?;
+            Ok(vals)
+        })
+    }
+}
+
+/// Converts the fields of a row, flattening any groups.  Each field of a
+/// list of structs becomes a JSON array.
+fn push_values(row: &Row, types: &[TypePtr], vals: &mut Vec<Value>) -> Result<()> {
+    for ((_, field), ty) in row.get_column_iter().zip(types) {
+        if let Some(struct_fields) = struct_list_fields(ty) {
+            let elems = match field {
+                Field::ListInternal(x) => x.elements(),
+                _ => {
+                    vals.extend(struct_fields.iter().map(|_| Value::Null));
+                    continue;
+                }
+            };
+            for i in 0..struct_fields.len() {
+                let values = elems
+                    .iter()
+                    .map(|elem| match elem {
+                        Field::Group(x) => field_to_json(x.get_column_iter().nth(i).unwrap().1),
+                        _ => Ok(serde_json::Value::Null),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                vals.push(Value::Text(serde_json::Value::Array(values).to_string()));
+            }
+            continue;
+        }
+        match field {
+            Field::Group(x) => push_values(x, ty.get_fields(), vals)?,
+            x => vals.push(field_to_value(x)?),
+        }
+    }
+    Ok(())
+}
+
+/// If this is a list of structs, the fields of the structs
+fn struct_list_fields(ty: &TypePtr) -> Option<&[TypePtr]> {
+    if !ty.is_group() {
+        return None;
+    }
+    let list = ty.get_fields().first()?;
+    if !list.is_group()
+        || list.get_basic_info().repetition() != parquet::basic::Repetition::REPEATED
+    {
+        return None;
+    }
+    let element = list.get_fields().first()?;
+    element.is_group().then(|| element.get_fields())
+}
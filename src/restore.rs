@@ -0,0 +1,143 @@
+use crate::read::ParquetFile;
+use crate::{quote, Column, LogicalType, PhysicalType, Result};
+use anyhow::Context;
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use std::path::Path;
+
+/// Loads a parquet file back into sqlite.
+///
+/// The rows are inserted into the table named in the parquet schema, in a
+/// single transaction.  The table is created first: by running `create_sql`
+/// if given (eg. the original `CREATE TABLE` statement, which you might get
+/// from [`read_sqlite_schema()`]), or otherwise with column types derived
+/// from the parquet schema.  Returns the number of rows inserted.
+pub fn restore_table(conn: &Connection, path: &Path, create_sql: Option<&str>) -> Result<u64> {
+    let file = ParquetFile::open(path)?;
+    let table = quote(&file.name);
+    let tx = conn.unchecked_transaction()?;
+    match create_sql {
+        Some(sql) => tx.execute_batch(sql).context(sql.to_string())?,
+        None => {
+            let defs = file
+                .cols
+                .iter()
+                .map(|col| {
+                    let not_null = if col.required { " NOT NULL" } else { "" };
+                    format!("{} {}{not_null}", quote(&col.name), declared_type(col))
+                })
+                .collect::<Vec<_>>();
+            tx.execute(&format!("CREATE TABLE {table} ({})", defs.join(", ")), [])?;
+        }
+    }
+
+    let names = file.cols.iter().map(|x| quote(&x.name)).collect::<Vec<_>>();
+    let mut insert = tx.prepare(&format!(
+        "INSERT INTO {table} ({}) VALUES (?{})",
+        names.join(", "),
+        ", ?".repeat(names.len() - 1),
+    ))?;
+    let mut n_rows = 0;
+    for row in file.rows() {
+        insert.execute(rusqlite::params_from_iter(row?))?;
+        n_rows += 1;
+    }
+    drop(insert);
+    tx.commit()?;
+    Ok(n_rows)
+}
+
+/// A type name which gives back the same column when the schema is inferred
+/// again
+fn declared_type(col: &Column) -> String {
+    match (col.logical_type, col.physical_type) {
+        // Lists and maps are read back as JSON arrays and objects
+        _ if col.list || col.map || col.name.contains("[].") => "JSON".into(),
+        (Some(LogicalType::String), _) => "TEXT".into(),
+        (Some(LogicalType::Enum), _) if !col.values.is_empty() => {
+            let values = col
+                .values
+                .iter()
+                .map(|x| format!("'{}'", x.replace('\'', "''")))
+                .collect::<Vec<_>>();
+            format!(
+                "TEXT CHECK ({} IN ({}))",
+                quote(&col.name),
+                values.join(", ")
+            )
+        }
+        (Some(LogicalType::Enum), _) => "TEXT".into(),
+        (Some(LogicalType::Json), _) => "JSON".into(),
+        (Some(LogicalType::Bson), _) => "BSON".into(),
+        (Some(LogicalType::Uuid), _) => "UUID".into(),
+        (Some(LogicalType::Date), _) => "DATE".into(),
+        (Some(LogicalType::Time(_)), _) => "TIME".into(),
+        (Some(LogicalType::Timestamp(_)), _) => "DATETIME".into(),
+        (
+            Some(LogicalType::Integer {
+                bit_width,
+                is_signed,
+            }),
+            _,
+        ) => {
+            let name = match bit_width {
+                8 => "TINYINT",
+                16 => "SMALLINT",
+                32 => "INTEGER",
+                _ => "BIGINT",
+            };
+            match is_signed {
+                true => name.into(),
+                false => format!("{name} UNSIGNED"),
+            }
+        }
+        (_, PhysicalType::Boolean) => "BOOL".into(),
+        (_, PhysicalType::Int32) => "INTEGER".into(),
+        (_, PhysicalType::Int64) => "BIGINT".into(),
+        (_, PhysicalType::Float) => "FLOAT".into(),
+        (_, PhysicalType::Double) => "REAL".into(),
+        (_, PhysicalType::ByteArray) => "BLOB".into(),
+        (_, PhysicalType::FixedLenByteArray(len)) => format!("BLOB({len})"),
+    }
+}
+
+/// An entry in the `sqlite_schema` table
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaObject {
+    /// "table", "index", "view", or "trigger"
+    pub kind: String,
+    pub name: String,
+    /// The table which this object belongs to
+    pub table: String,
+    /// `None` for automatically-created indices
+    pub sql: Option<String>,
+}
+
+/// Reads back a copy of the `sqlite_schema` table, as written when
+/// `sqlite_schema` is exported like any other table.
+pub fn read_sqlite_schema(path: &Path) -> Result<Vec<SchemaObject>> {
+    let file = ParquetFile::open(path)?;
+    let idx = |name: &str| {
+        file.cols
+            .iter()
+            .position(|x| x.name == name)
+            .ok_or_else(|| anyhow::anyhow!("{}: No {name} column", path.display()))
+    };
+    let (kind, name, table, sql) = (idx("type")?, idx("name")?, idx("tbl_name")?, idx("sql")?);
+    let text = |x: &Value| match x {
+        Value::Text(x) => Some(x.clone()),
+        Value::Blob(x) => String::from_utf8(x.clone()).ok(),
+        _ => None,
+    };
+    file.rows()
+        .map(|row| {
+            let row = row?;
+            Ok(SchemaObject {
+                kind: text(&row[kind]).unwrap_or_default(),
+                name: text(&row[name]).unwrap_or_default(),
+                table: text(&row[table]).unwrap_or_default(),
+                sql: text(&row[sql]),
+            })
+        })
+        .collect()
+}
@@ -1,6 +1,8 @@
-use crate::Result;
-use rusqlite::Connection;
+use crate::{quote, Result};
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{Connection, OptionalExtension};
 use std::fmt;
+use std::sync::Arc;
 use tracing::*;
 
 /// Infer a parquet schema to use for this dataset.
@@ -12,144 +14,1100 @@ use tracing::*;
 /// go over and fill in the missing values.  According the the sqlite schema,
 /// the columns are theoretically nullable; but _in fact_ there are no nulls.
 /// `sqlite2parquet` will infer that these columns are required.
+///
+/// The statistics for all the columns are gathered in a single scan of the
+/// table.  To avoid scanning the whole table, see
+/// [`infer_schema_with_options()`].
 pub fn infer_schema<'a>(
     conn: &'a Connection,
     table: &'a str,
 ) -> Result<impl Iterator<Item = Result<Column>> + 'a> {
+    infer_schema_with_options(conn, table, &InferOptions::default())
+}
+
+/// Settings for [`infer_schema_with_options()`]
+///
+/// Inference is deterministic: the same data with the same options always
+/// gives the same schema.
+#[derive(Debug, Clone)]
+pub struct InferOptions {
+    /// Only look at this many rows, spread evenly through the table, instead
+    /// of scanning the whole thing.
+    ///
+    /// A sample can't tell us for sure that a column has no nulls, or that
+    /// its values all fit in 32 bits, so in this case we go by the declared
+    /// schema: columns are only required if they're `NOT NULL`, and integers
+    /// are always 64-bit.
+    pub max_rows: Option<u64>,
+    /// The number of rows to sample when deciding whether to use dictionary
+    /// encoding
+    pub sample_size: usize,
+    /// Chooses which rows are sampled.  Changing the seed gives a different
+    /// (but still repeatable) sample.
+    pub seed: u64,
+    /// Use dictionary encoding if the number of distinct values in the
+    /// sample is less than this proportion of the number of rows sampled
+    pub dictionary_threshold: f64,
+    /// Split JSON columns whose values are objects into one column per
+    /// field, named `column.field`, which are written as a struct.  The
+    /// fields and their types are inferred from the sampled rows: fields
+    /// which don't appear in the sample are dropped.
+    pub shred_json: bool,
+    /// Text columns with at most this many distinct values are written as
+    /// enums.  Columns with a `CHECK (x IN (...))` constraint always are.
+    pub enum_max_values: Option<usize>,
+    /// Parse `BOOL` columns leniently: any non-zero number is true, and so
+    /// are texts like `'true'`, `'yes'`, and `'t'` (see
+    /// [`lenient_bool_query()`]).  Text columns which only hold spellings
+    /// of true and false are written as booleans too.  Otherwise `BOOL`
+    /// columns must hold 0 or 1, and text is left as it is.
+    pub lenient_bools: bool,
+}
+
+impl Default for InferOptions {
+    fn default() -> Self {
+        InferOptions {
+            max_rows: None,
+            sample_size: 1000,
+            seed: 0,
+            dictionary_threshold: 0.75,
+            shred_json: false,
+            enum_max_values: None,
+            lenient_bools: false,
+        }
+    }
+}
+
+/// Like [`infer_schema()`], but with some control over how the data is
+/// examined.
+pub fn infer_schema_with_options<'a>(
+    conn: &'a Connection,
+    table: &'a str,
+    opts: &InferOptions,
+) -> Result<impl Iterator<Item = Result<Column>> + 'a> {
+    let t_start = std::time::Instant::now();
     let mut table_info = conn.prepare(&format!("SELECT * FROM pragma_table_info('{}')", table))?;
-    let infos: Vec<(String, String, Option<i32>, bool)> = table_info
+    let decls: Vec<(String, Decl)> = table_info
         .query_map([], |row| {
             let name: String = row.get(1)?;
             let type_string: String = row.get(2)?;
-            let (type_name, type_len) = if let Some((x, y)) = type_string.split_once(['[', '(']) {
-                let len: i32 = y.strip_suffix([']', ')']).unwrap().parse().unwrap();
-                (x, Some(len))
-            } else {
-                (type_string.as_str(), None)
-            };
-            let type_name = type_name.to_uppercase();
             let not_null: bool = row.get(3)?;
-            Ok((name, type_name, type_len, not_null))
+            Ok((name, Decl::parse(&type_string, not_null)))
         })?
         .collect::<rusqlite::Result<_>>()?;
-    Ok(infos
+    let names = decls.iter().map(|x| x.0.as_str()).collect::<Vec<_>>();
+    let scan = TableScan::table(conn, table, &names, opts)?;
+    let sql: Option<String> = conn
+        .query_row(
+            "SELECT sql FROM sqlite_schema WHERE type = 'table' AND name = ?1",
+            [table],
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten();
+    let checks = sql.as_deref().map(enum_checks).unwrap_or_default();
+    let cols = decls
         .into_iter()
-        .map(move |(name, type_name, type_len, not_null)| {
-            let _g = info_span!("", table=%name).entered();
-            // If the schema says it's "NOT NULL" then we know there are no nulls.
-            // If the schema allows nulls then we should check to see if there
-            // actually are any in the data.
-            let required: bool = not_null
-                || conn.query_row(
-                    &format!("SELECT COUNT(*) == 0 FROM {table} WHERE {name} IS NULL"),
-                    [],
-                    |x| x.get(0),
-                )?;
-
-            let infer_integer = || {
-                let (min, max): (Option<i64>, Option<i64>) = conn.query_row(
-                    &format!("SELECT MIN({name}), MAX({name}) FROM {table}"),
-                    [],
-                    |x| Ok((x.get(0)?, x.get(1)?)),
-                )?;
-                if max.unwrap_or(0) <= i64::from(i32::MAX)
-                    && min.unwrap_or(0) >= i64::from(i32::MIN)
-                {
-                    anyhow::Ok(PhysicalType::Int32)
-                } else {
-                    anyhow::Ok(PhysicalType::Int64)
+        .enumerate()
+        .flat_map(|(i, (name, mut decl))| {
+            decl.values = checks.get(&name.to_lowercase()).cloned();
+            let query = format!("SELECT {} FROM {} ORDER BY rowid", name, table);
+            let col = scan.infer_column(i, name, &decl, query, opts);
+            let select = |x: &str| format!("SELECT {x} FROM {} ORDER BY rowid", table);
+            scan.shred_json(i, col, select, opts).into_iter().map(Ok)
+        })
+        .collect::<Vec<_>>();
+    debug!("Inferred schema for {table} in {:?}", t_start.elapsed());
+    Ok(cols.into_iter())
+}
+
+/// Infer a parquet schema for the results of a query.
+///
+/// This works like [`infer_schema()`], but for any `SELECT`: eg. a join or
+/// an aggregation.  sqlite can tell us the declared type of a result column
+/// when it comes straight from a table column; for other columns (eg.
+/// `COUNT(*)`) the type is inferred from the values.  The query is run once,
+/// to gather statistics about the values.
+///
+/// Each column's `query` selects that column from the original query, so
+/// you can pass the result to [`write_table()`](crate::write_table()).  That
+/// runs the query once per column, however: with
+/// [`write_query()`](crate::write_query()) it's only run once.
+///
+/// ```rust
+/// # let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// # conn.execute_batch("CREATE TABLE orders (customer TEXT NOT NULL, amount REAL);
+/// #     INSERT INTO orders VALUES ('alice', 1.5), ('bob', 2.0), ('alice', 3.0);").unwrap();
+/// let sql = "SELECT customer, COUNT(*) AS n, SUM(amount) AS total FROM orders GROUP BY customer";
+/// let cols = sqlite2parquet::infer_query_schema(&conn, sql)
+///     .unwrap()
+///     .collect::<anyhow::Result<Vec<_>>>()
+///     .unwrap();
+/// assert_eq!(cols[0].logical_type, Some(sqlite2parquet::LogicalType::String));
+/// assert_eq!(cols[1].physical_type, sqlite2parquet::PhysicalType::Int32);
+/// assert_eq!(cols[2].physical_type, sqlite2parquet::PhysicalType::Double);
+/// # let dir = tempfile::tempdir().unwrap();
+/// let out = std::fs::File::create(dir.path().join("orders_by_customer.parquet")).unwrap();
+/// let opts = sqlite2parquet::WriteOptions::default();
+/// sqlite2parquet::write_query(&conn, "orders_by_customer", sql, &cols, &opts, &out, 1000)
+///     .unwrap();
+/// ```
+pub fn infer_query_schema(
+    conn: &Connection,
+    sql: &str,
+) -> Result<impl Iterator<Item = Result<Column>>> {
+    infer_query_schema_with_options(conn, sql, &InferOptions::default())
+}
+
+/// Like [`infer_query_schema()`], but with some control over how the data
+/// is examined.  If [`max_rows`](InferOptions::max_rows) is set, only the
+/// first rows of the result are looked at.
+pub fn infer_query_schema_with_options(
+    conn: &Connection,
+    sql: &str,
+    opts: &InferOptions,
+) -> Result<impl Iterator<Item = Result<Column>>> {
+    let t_start = std::time::Instant::now();
+    let sql = sql.trim().trim_end_matches(';');
+    let mut stmnt = conn.prepare(sql)?;
+    let decls = stmnt
+        .columns()
+        .iter()
+        .map(|col| {
+            let decl = Decl::parse(col.decl_type().unwrap_or(""), false);
+            (col.name().to_string(), decl)
+        })
+        .collect::<Vec<_>>();
+    let scan = TableScan::query(&mut stmnt, opts)?;
+    let cols = decls
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, (name, decl))| {
+            let query = format!("SELECT {} FROM ({sql})", quote(&name));
+            let col = scan.infer_column(i, name, &decl, query, opts);
+            let select = |x: &str| format!("SELECT {x} FROM ({sql})");
+            scan.shred_json(i, col, select, opts).into_iter().map(Ok)
+        })
+        .collect::<Vec<_>>();
+    debug!("Inferred schema for query in {:?}", t_start.elapsed());
+    Ok(cols.into_iter())
+}
+
+/// A child table of key/value pairs (eg. `entity_attrs(entity_id, key,
+/// value)`), to be folded into a map column on its parent table.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct KeyValueTable {
+    /// The child table
+    pub table: String,
+    /// The column of the child table which refers to the parent
+    pub foreign_key: String,
+    /// The column of the parent table which `foreign_key` refers to.  If
+    /// not given, the parent's rowid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub references: Option<String>,
+    /// The column holding the keys, which are converted to strings
+    pub key: String,
+    /// The column holding the values
+    pub value: String,
+}
+
+/// Makes a map column for the table `parent`, with an entry for each row of
+/// the key/value table which refers to it.
+///
+/// The type of the values is inferred from the child table.  Parent rows
+/// with no entries get an empty map, so the column is required.  The
+/// child table is queried once per parent row, so `foreign_key` should be
+/// indexed.  The values can't be blobs, since the entries are gathered into
+/// a JSON object.
+///
+/// ```rust
+/// # let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// # conn.execute_batch("CREATE TABLE entities (id INTEGER PRIMARY KEY, name TEXT);
+/// #     CREATE TABLE entity_attrs (entity_id INTEGER, key TEXT, value TEXT);
+/// #     INSERT INTO entities VALUES (1, 'foo'), (2, 'bar');
+/// #     INSERT INTO entity_attrs VALUES (1, 'colour', 'red'), (1, 'size', 'L');").unwrap();
+/// let attrs = sqlite2parquet::KeyValueTable {
+///     table: "entity_attrs".to_string(),
+///     foreign_key: "entity_id".to_string(),
+///     references: Some("id".to_string()),
+///     key: "key".to_string(),
+///     value: "value".to_string(),
+/// };
+/// let mut cols = sqlite2parquet::infer_schema(&conn, "entities")
+///     .unwrap()
+///     .collect::<anyhow::Result<Vec<_>>>()
+///     .unwrap();
+/// cols.push(sqlite2parquet::infer_map_column(&conn, "entities", "attrs", &attrs, &Default::default()).unwrap());
+/// # let dir = tempfile::tempdir().unwrap();
+/// let out = std::fs::File::create(dir.path().join("entities.parquet")).unwrap();
+/// sqlite2parquet::write_table(&conn, "entities", &cols, &out, 1000).unwrap();
+/// ```
+pub fn infer_map_column(
+    conn: &Connection,
+    parent: &str,
+    name: &str,
+    kv: &KeyValueTable,
+    opts: &InferOptions,
+) -> Result<Column> {
+    let (child, key, value) = (quote(&kv.table), quote(&kv.key), quote(&kv.value));
+    let opts = InferOptions {
+        shred_json: false,
+        ..opts.clone()
+    };
+    let values =
+        infer_query_schema_with_options(conn, &format!("SELECT {value} FROM {child}"), &opts)?
+            .next()
+            .expect("The query has one column")?;
+    // `json_group_object()` fails on blobs, so catch them here, rather than
+    // partway through writing the file
+    let has_blobs = conn
+        .prepare(&format!(
+            "SELECT 1 FROM {child} WHERE typeof({value}) = 'blob' LIMIT 1"
+        ))?
+        .exists([])?;
+    if has_blobs {
+        anyhow::bail!("{}.{}: Map values can't be blobs", kv.table, kv.value);
+    }
+    let references = kv.references.as_deref().map_or("rowid".into(), quote);
+    let parent = quote(parent);
+    // The entries are sorted by key, so the output doesn't depend on the
+    // order of the child table
+    let query = format!(
+        "SELECT (SELECT json_group_object(k, v) FROM \
+            (SELECT CAST({key} AS TEXT) AS k, {value} AS v FROM {child} \
+            WHERE {child}.{} = {parent}.{references} ORDER BY k)) \
+        FROM {parent} ORDER BY rowid",
+        quote(&kv.foreign_key),
+    );
+    Ok(Column {
+        name: name.to_string(),
+        required: true,
+        physical_type: values.physical_type,
+        logical_type: values.logical_type,
+        encoding: values.encoding,
+        dictionary: values.dictionary,
+        query,
+        list: false,
+        map: true,
+        values: values.values,
+    })
+}
+
+/// The declared type of a column: eg. `VARCHAR(15) NOT NULL`
+struct Decl {
+    /// In upper case
+    type_name: String,
+    type_len: Option<i32>,
+    not_null: bool,
+    /// The values allowed by a `CHECK (x IN (...))` constraint
+    values: Option<Vec<String>>,
+    /// Declared as eg. `INTEGER UNSIGNED`.  The `UNSIGNED` isn't part of
+    /// `type_name`.
+    unsigned: bool,
+}
+
+impl Decl {
+    fn parse(type_string: &str, not_null: bool) -> Decl {
+        let (type_name, type_len) = if let Some((x, y)) = type_string.split_once(['[', '(']) {
+            // Lengths we don't understand (eg. `DECIMAL(10,2)`) are ignored
+            let len = y.strip_suffix([']', ')']).and_then(|y| y.parse().ok());
+            (x.trim(), len)
+        } else {
+            (type_string, None)
+        };
+        let type_name = type_name.to_uppercase();
+        let words = type_name.split_whitespace().collect::<Vec<_>>();
+        let unsigned = words.contains(&"UNSIGNED");
+        Decl {
+            type_name: match unsigned {
+                true => words
+                    .into_iter()
+                    .filter(|&x| x != "UNSIGNED")
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                false => type_name,
+            },
+            type_len,
+            not_null,
+            values: None,
+            unsigned,
+        }
+    }
+}
+
+/// Wraps a query so that its results are parsed as booleans leniently: any
+/// non-zero number is true, and texts like `'true'`, `'Yes'`, and `' f '`
+/// are understood.  Other values are passed through unchanged, so they fail
+/// to convert when the column is written.  The original spellings are lost,
+/// so [`verify_table()`](crate::verify_table()) counts the values which
+/// were parsed this way.
+///
+/// ```rust
+/// # let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// # conn.execute_batch("CREATE TABLE flags (x);
+/// #     INSERT INTO flags VALUES ('yes'), ('F'), (2), (NULL);").unwrap();
+/// let query = sqlite2parquet::lenient_bool_query("SELECT x FROM flags ORDER BY rowid");
+/// let mut stmnt = conn.prepare(&query).unwrap();
+/// let xs = stmnt
+///     .query_map([], |row| row.get::<_, Option<bool>>(0))
+///     .unwrap()
+///     .collect::<rusqlite::Result<Vec<_>>>()
+///     .unwrap();
+/// assert_eq!(xs, [Some(true), Some(false), Some(true), None]);
+/// ```
+pub fn lenient_bool_query(query: &str) -> String {
+    let list = |texts: &[&str]| {
+        texts
+            .iter()
+            .map(|x| format!("'{x}'"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    format!(
+        "WITH _b(x) AS ({}) SELECT CASE \
+            WHEN typeof(x) IN ('integer', 'real') THEN x != 0 \
+            WHEN lower(trim(x)) IN ({}) THEN 1 \
+            WHEN lower(trim(x)) IN ({}) THEN 0 \
+            ELSE x END FROM _b",
+        query.trim().trim_end_matches(';'),
+        list(crate::conversion::TRUE_TEXTS),
+        list(crate::conversion::FALSE_TEXTS),
+    )
+}
+
+/// Finds the `CHECK (x IN ('a', 'b', ...))` constraints in a `CREATE TABLE`
+/// statement.  The keys are the column names, in lower case.
+fn enum_checks(sql: &str) -> std::collections::HashMap<String, Vec<String>> {
+    let tokens = tokenize(sql);
+    let mut checks = std::collections::HashMap::new();
+    for (i, _) in tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| matches!(x, Token::Word(x) if x.eq_ignore_ascii_case("CHECK")))
+    {
+        // Skip any extra parentheses around the expression
+        let mut rest = &tokens[i + 1..];
+        let mut depth = 0;
+        while rest.first() == Some(&Token::Punct('(')) {
+            rest = &rest[1..];
+            depth += 1;
+        }
+        let (
+            Some(Token::Word(col) | Token::Quoted(col)),
+            Some(Token::Word(kw)),
+            Some(Token::Punct('(')),
+        ) = (rest.first(), rest.get(1), rest.get(2))
+        else {
+            continue;
+        };
+        if depth == 0 || !kw.eq_ignore_ascii_case("IN") {
+            continue;
+        }
+        let mut values = vec![];
+        let mut rest = &rest[3..];
+        while let Some(Token::Str(x)) = rest.first() {
+            values.push(x.clone());
+            match rest.get(1) {
+                Some(Token::Punct(',')) => rest = &rest[2..],
+                _ => {
+                    rest = &rest[1..];
+                    break;
                 }
-            };
-            let physical_type = match type_name.as_str() {
-                "BOOL" => PhysicalType::Boolean,
-                "DATE" => PhysicalType::Int32,
-                "TIME" => PhysicalType::Int64,
-                "DATETIME" | "TIMESTAMP" => PhysicalType::Int64,
-                "UUID" => PhysicalType::FixedLenByteArray(16),
-                "INTERVAL" => PhysicalType::FixedLenByteArray(12),
-                "BIGINT" | "SMALLINT" | "NUM" | "NUMBER" => infer_integer()?,
-                x if x.starts_with("INT") => infer_integer()?,
-                // parquet-rs doesn't allow us to back LogicalType::String
-                // columns with PhysicalType::FixedLenByteArray, so if a column
-                // is declared as eg. TEXT[15] we need to decide whether to
-                // preserve the fixed-length property or the information that
-                // this byte array is a string.  Here we plumb for "string".
-                "TEXT" | "CHAR" | "VARCHAR" | "NVARCHAR" => PhysicalType::ByteArray,
-                "BLOB" | "BINARY" | "VARBINARY" => {
-                    if let Some(len) = type_len {
-                        PhysicalType::FixedLenByteArray(len)
-                    } else {
-                        PhysicalType::ByteArray
+            }
+        }
+        let closed = rest.len() > depth && rest[..=depth].iter().all(|x| x == &Token::Punct(')'));
+        if closed && !values.is_empty() {
+            checks.insert(col.to_lowercase(), values);
+        }
+    }
+    checks
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    /// A keyword or bare identifier
+    Word(String),
+    /// An identifier in double quotes, backticks, or square brackets
+    Quoted(String),
+    /// A string literal
+    Str(String),
+    Punct(char),
+}
+
+/// Splits SQL into tokens, roughly.  Comments and whitespace are skipped.
+fn tokenize(sql: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            _ if c.is_whitespace() => (),
+            '-' if chars.peek() == Some(&'-') => {
+                chars.by_ref().find(|&x| x == '\n');
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = ' ';
+                for x in chars.by_ref() {
+                    if prev == '*' && x == '/' {
+                        break;
+                    }
+                    prev = x;
+                }
+            }
+            '\'' | '"' | '`' | '[' => {
+                let end = if c == '[' { ']' } else { c };
+                let mut text = String::new();
+                while let Some(x) = chars.next() {
+                    if x == end {
+                        // Quotes are escaped by doubling them
+                        if end != ']' && chars.peek() == Some(&end) {
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    text.push(x);
+                }
+                tokens.push(match c {
+                    '\'' => Token::Str(text),
+                    _ => Token::Quoted(text),
+                });
+            }
+            _ if c.is_alphanumeric() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some(&x) = chars.peek() {
+                    if !(x.is_alphanumeric() || x == '_' || x == '$') {
+                        break;
+                    }
+                    word.push(x);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+            _ => tokens.push(Token::Punct(c)),
+        }
+    }
+    tokens
+}
+
+/// The results of reading through a table
+struct TableScan {
+    /// One per column
+    stats: Vec<ColumnStats>,
+    /// Some of the rows, chosen pseudo-randomly according to the seed
+    sample: Vec<Vec<Value>>,
+    /// True if we only looked at some of the rows
+    sampled: bool,
+    n_seen: u64,
+    sample_size: usize,
+    rng: Rng,
+}
+
+/// Statistics about the values in a column
+#[derive(Default)]
+struct ColumnStats {
+    n_rows: u64,
+    n_nulls: u64,
+    n_integers: u64,
+    n_reals: u64,
+    n_texts: u64,
+    /// Texts which are spellings of true or false
+    n_bool_texts: u64,
+    min_int: Option<i64>,
+    max_int: Option<i64>,
+    /// The distinct text values, if we're looking for enums.  `None` once
+    /// there are more than `max_distinct`.
+    distinct_texts: Option<std::collections::BTreeSet<String>>,
+    max_distinct: usize,
+}
+
+impl TableScan {
+    fn new(n_cols: usize, opts: &InferOptions) -> TableScan {
+        TableScan {
+            stats: (0..n_cols)
+                .map(|_| ColumnStats {
+                    distinct_texts: opts.enum_max_values.map(|_| Default::default()),
+                    max_distinct: opts.enum_max_values.unwrap_or(0),
+                    ..ColumnStats::default()
+                })
+                .collect(),
+            sample: vec![],
+            sampled: false,
+            n_seen: 0,
+            sample_size: opts.sample_size,
+            rng: Rng(opts.seed),
+        }
+    }
+
+    /// Reads the whole table, or a sample of it if `max_rows` is set
+    fn table(
+        conn: &Connection,
+        table: &str,
+        names: &[&str],
+        opts: &InferOptions,
+    ) -> Result<TableScan> {
+        let mut scan = TableScan::new(names.len(), opts);
+        let cols = names
+            .iter()
+            .map(|x| quote(x))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let pk = conn
+            .prepare("SELECT name FROM pragma_table_info(?1) WHERE pk > 0 ORDER BY pk")?
+            .query_map([table], |x| x.get::<_, String>(0))?
+            .map(|x| Ok(quote(&x?)))
+            .collect::<Result<Vec<_>>>()?;
+        let table = quote(table);
+        let rowid_range = conn
+            .query_row(
+                &format!("SELECT MIN(rowid), MAX(rowid) FROM {table}"),
+                [],
+                |x| Ok((x.get::<_, Option<i64>>(0)?, x.get::<_, Option<i64>>(1)?)),
+            )
+            .ok();
+        // The rows are read in a fixed order, so that the sample doesn't
+        // depend on the query plan (which can change when an index is added,
+        // for example)
+        let order_by = match (&rowid_range, pk.is_empty()) {
+            (Some(_), _) => "ORDER BY rowid".to_string(),
+            (None, false) => format!("ORDER BY {}", pk.join(", ")),
+            (None, true) => String::new(),
+        };
+        match (opts.max_rows, rowid_range) {
+            // The rowids can span more than an i64 can hold
+            (Some(max_rows), Some((Some(min), Some(max))))
+                if max.abs_diff(min) >= max_rows && max_rows > 0 =>
+            {
+                // Read rows at evenly-spaced rowids
+                scan.sampled = true;
+                let stride = max.abs_diff(min) as f64 / max_rows as f64;
+                let mut stmnt = conn.prepare(&format!(
+                    "SELECT rowid, {cols} FROM {table} WHERE rowid >= ?1 ORDER BY rowid LIMIT 1"
+                ))?;
+                let mut last_rowid = None;
+                for i in 0..max_rows {
+                    let target = min.saturating_add_unsigned((i as f64 * stride) as u64);
+                    let mut rows = stmnt.query([target])?;
+                    let Some(row) = rows.next()? else { continue };
+                    let rowid: i64 = row.get(0)?;
+                    if last_rowid == Some(rowid) {
+                        continue;
                     }
+                    last_rowid = Some(rowid);
+                    scan.add_row(row, 1)?;
                 }
-                "JSON" | "BSON" => PhysicalType::ByteArray,
-                "FLOAT" => PhysicalType::Float,
-                "REAL" | "DOUBLE" => PhysicalType::Double,
-                x => {
-                    warn!("Unknown type: {x}");
-                    PhysicalType::ByteArray
+            }
+            (Some(max_rows), None) => {
+                // WITHOUT ROWID tables: just take the first rows
+                scan.sampled = true;
+                let mut stmnt =
+                    conn.prepare(&format!("SELECT {cols} FROM {table} {order_by} LIMIT ?1"))?;
+                let mut rows = stmnt.query([max_rows])?;
+                while let Some(row) = rows.next()? {
+                    scan.add_row(row, 0)?;
                 }
+            }
+            _ => {
+                let mut stmnt = conn.prepare(&format!("SELECT {cols} FROM {table} {order_by}"))?;
+                let mut rows = stmnt.query([])?;
+                while let Some(row) = rows.next()? {
+                    scan.add_row(row, 0)?;
+                }
+            }
+        }
+        Ok(scan)
+    }
+
+    /// Runs the query, and reads all the results (or the first `max_rows`)
+    fn query(stmnt: &mut rusqlite::Statement, opts: &InferOptions) -> Result<TableScan> {
+        let mut scan = TableScan::new(stmnt.column_count(), opts);
+        let mut rows = stmnt.query([])?;
+        while let Some(row) = rows.next()? {
+            if opts.max_rows.is_some_and(|x| scan.n_seen >= x) {
+                scan.sampled = true;
+                break;
+            }
+            scan.add_row(row, 0)?;
+        }
+        Ok(scan)
+    }
+
+    /// Adds a row to the statistics.  The values start at column `first`.
+    fn add_row(&mut self, row: &rusqlite::Row, first: usize) -> Result<()> {
+        for (i, stats) in self.stats.iter_mut().enumerate() {
+            stats.add(row.get_ref(first + i)?);
+        }
+        // Reservoir sampling: every row has an equal chance of being kept
+        let slot = if self.sample.len() < self.sample_size {
+            self.sample.push(vec![]);
+            Some(self.sample.len() - 1)
+        } else {
+            Some(self.rng.below(self.n_seen + 1) as usize).filter(|&x| x < self.sample_size)
+        };
+        if let Some(slot) = slot {
+            self.sample[slot] = (first..first + self.stats.len())
+                .map(|i| Ok(Value::from(row.get_ref(i)?)))
+                .collect::<Result<_>>()?;
+        }
+        self.n_seen += 1;
+        Ok(())
+    }
+
+    /// Decides on the type of the `i`th column
+    fn infer_column(
+        &self,
+        i: usize,
+        name: String,
+        decl: &Decl,
+        query: String,
+        opts: &InferOptions,
+    ) -> Column {
+        let _g = info_span!("", table=%name).entered();
+        let stats = &self.stats[i];
+        let (type_name, type_len) = (&decl.type_name, decl.type_len);
+        // If the schema says it's "NOT NULL" then we know there are no nulls.
+        // If the schema allows nulls then we should check to see if there
+        // actually are any in the data.
+        let required: bool = decl.not_null || (!self.sampled && stats.n_nulls == 0);
+
+        // Integers get the narrowest type which holds all the values.  They're
+        // only unsigned if they're declared so: a column which happens to
+        // have no negative values yet may well get some later.
+        let infer_integer = || {
+            if self.sampled {
+                return (PhysicalType::Int64, None);
+            }
+            let (min, max) = (stats.min_int.unwrap_or(0), stats.max_int.unwrap_or(0));
+            if decl.unsigned && min < 0 {
+                warn!("Negative values in an UNSIGNED column: {min}");
+            }
+            let is_signed = !decl.unsigned || min < 0;
+            let fits = |bits: u32| match is_signed {
+                true => min >= -(1 << (bits - 1)) && max < 1 << (bits - 1),
+                false => max < 1 << bits,
             };
-            match (type_len, physical_type.len()) {
-                (Some(len), None) => warn!("Ignoring length annotation: {type_name}[{len}]"),
-                (Some(len1), Some(len2)) if len1 != len2 => warn!(
-                    "Overriding length annotation: {type_name}[{len1}] -> \
-                    {type_name}[{len2}]"
-                ),
-                _ => (),
-            }
-            let logical_type = match type_name.as_str() {
-                "TEXT" | "CHAR" | "VARCHAR" | "NVARCHAR" => Some(LogicalType::String),
-                "DATE" => Some(LogicalType::Date),
-                "TIME" => Some(LogicalType::Time(TimeType {
-                    utc: false,
-                    unit: TimeUnit::Nanos,
-                })),
-                "DATETIME" | "TIMESTAMP" => Some(LogicalType::Timestamp(TimeType {
-                    utc: true,
-                    unit: TimeUnit::Nanos,
-                })),
-                "UUID" => Some(LogicalType::Uuid),
-                "JSON" => Some(LogicalType::Json),
-                "BSON" => Some(LogicalType::Bson),
-                _ => None,
+            // Unsigned 32-bit values are stored as i32s, so the ones which
+            // don't fit in an i32 need an Int64
+            let bit_width = match () {
+                _ if fits(8) => 8,
+                _ if fits(16) => 16,
+                _ if fits(31) || (is_signed && fits(32)) => 32,
+                _ => 64,
+            };
+            let physical_type = match bit_width {
+                64 => PhysicalType::Int64,
+                _ => PhysicalType::Int32,
+            };
+            let logical_type = LogicalType::Integer {
+                bit_width,
+                is_signed,
             };
+            (physical_type, Some(logical_type))
+        };
+        // Columns with no declared type can hold anything, but often
+        // they're used consistently
+        let n_values = stats.n_rows - stats.n_nulls;
+        let unknown = |x: &str| {
+            match () {
+                _ if self.sampled || n_values == 0 => None,
+                _ if stats.n_integers == n_values => Some(infer_integer()),
+                // Like REAL affinity, integers are read as floats if the
+                // column also holds reals
+                _ if stats.n_integers + stats.n_reals == n_values => {
+                    Some((PhysicalType::Double, None))
+                }
+                _ if stats.n_texts == n_values => {
+                    Some((PhysicalType::ByteArray, Some(LogicalType::String)))
+                }
+                _ => None,
+            }
+            .unwrap_or_else(|| {
+                warn!("Unknown type: {x}");
+                (PhysicalType::ByteArray, None)
+            })
+        };
+        let (physical_type, inferred_logical_type) = match type_name.as_str() {
+            "BOOL" | "BOOLEAN" => (PhysicalType::Boolean, None),
+            "DATE" => (PhysicalType::Int32, None),
+            "TIME" => (PhysicalType::Int64, None),
+            "DATETIME" | "TIMESTAMP" => (PhysicalType::Int64, None),
+            "UUID" => (PhysicalType::FixedLenByteArray(16), None),
+            "INTERVAL" => (PhysicalType::FixedLenByteArray(12), None),
+            "BIGINT" | "BIG INT" | "SMALLINT" | "TINYINT" | "MEDIUMINT" | "NUM" | "NUMBER" => {
+                infer_integer()
+            }
+            x if x.starts_with("INT") => infer_integer(),
+            // parquet-rs doesn't allow us to back LogicalType::String
+            // columns with PhysicalType::FixedLenByteArray, so if a column
+            // is declared as eg. TEXT[15] we need to decide whether to
+            // preserve the fixed-length property or the information that
+            // this byte array is a string.  Here we plumb for "string".
+            "TEXT" | "CHAR" | "VARCHAR" | "NVARCHAR" => (PhysicalType::ByteArray, None),
+            "BLOB" | "BINARY" | "VARBINARY" => {
+                if let Some(len) = type_len {
+                    (PhysicalType::FixedLenByteArray(len), None)
+                } else {
+                    (PhysicalType::ByteArray, None)
+                }
+            }
+            "JSON" | "BSON" => (PhysicalType::ByteArray, None),
+            "FLOAT" => (PhysicalType::Float, None),
+            "REAL" | "DOUBLE" => (PhysicalType::Double, None),
+            x => unknown(x),
+        };
+        match (type_len, physical_type.len()) {
+            (Some(len), None) => warn!("Ignoring length annotation: {type_name}[{len}]"),
+            (Some(len1), Some(len2)) if len1 != len2 => warn!(
+                "Overriding length annotation: {type_name}[{len1}] -> \
+                {type_name}[{len2}]"
+            ),
+            _ => (),
+        }
+        let logical_type = match type_name.as_str() {
+            "TEXT" | "CHAR" | "VARCHAR" | "NVARCHAR" => Some(LogicalType::String),
+            "DATE" => Some(LogicalType::Date),
+            "TIME" => Some(LogicalType::Time(TimeType {
+                utc: false,
+                unit: TimeUnit::Nanos,
+            })),
+            "DATETIME" | "TIMESTAMP" => Some(LogicalType::Timestamp(TimeType {
+                utc: true,
+                unit: TimeUnit::Nanos,
+            })),
+            "UUID" => Some(LogicalType::Uuid),
+            "JSON" => Some(LogicalType::Json),
+            "BSON" => Some(LogicalType::Bson),
+            _ => inferred_logical_type,
+        };
+
+        // Columns which only hold 0 and 1 are booleans, and so are ones
+        // which only hold spellings of true and false if we're parsing them
+        // leniently (since the spellings are lost).  Integer columns with no
+        // zeros aren't, since they're more likely to be ids.  Unless they're
+        // declared `BOOL`, we need to have seen every value: a sample could
+        // easily miss a 2, or a `'maybe'`.
+        let is_integer = stats.n_integers == n_values
+            && matches!(logical_type, Some(LogicalType::Integer { .. }));
+        let is_text = stats.n_texts == n_values
+            && physical_type == PhysicalType::ByteArray
+            && matches!(logical_type, None | Some(LogicalType::String));
+        let looks_boolean = !self.sampled
+            && n_values > 0
+            && decl.values.is_none()
+            && ((is_integer && stats.min_int == Some(0) && stats.max_int == Some(1))
+                || (opts.lenient_bools && is_text && stats.n_bool_texts == n_values));
+        let (physical_type, logical_type) = match looks_boolean {
+            true => (PhysicalType::Boolean, None),
+            false => (physical_type, logical_type),
+        };
+        let query = match physical_type {
+            PhysicalType::Boolean if opts.lenient_bools => lenient_bool_query(&query),
+            _ => query,
+        };
 
-            // TODO: Try to figure out when to do DELTA_BINARY_PACKED and when
-            // to leave it as RLE
-            let encoding = None;
+        // TODO: Try to figure out when to do DELTA_BINARY_PACKED and when
+        // to leave it as RLE
+        let encoding = None;
 
-            let dictionary = match physical_type {
-                PhysicalType::Boolean => false,
-                _ => {
-                    // Sample 1000 rows randomly and check how many of them are unique
-                    let prop_unique: Option<f64> = conn.query_row(
-                        &format!(
-                            "SELECT CAST(COUNT(DISTINCT {name}) as REAL) / COUNT(*) FROM \
-                    (SELECT {name} FROM {table} ORDER BY RANDOM() LIMIT 1000)"
-                        ),
-                        [],
-                        |x| x.get(0),
-                    )?;
-                    prop_unique.map_or(false, |x| x < 0.75)
+        let dictionary = match physical_type {
+            PhysicalType::Boolean => false,
+            _ => {
+                // Check how many of the sampled values are unique
+                let mut distinct = std::collections::HashSet::new();
+                for row in &self.sample {
+                    if let Some(key) = ValueKey::new(&row[i]) {
+                        distinct.insert(key);
+                    }
                 }
+                let prop_unique = distinct.len() as f64 / self.sample.len() as f64;
+                !self.sample.is_empty() && prop_unique < opts.dictionary_threshold
+            }
+        };
+
+        // Text columns with a fixed set of values are enums
+        let is_text = matches!(
+            (physical_type, logical_type),
+            (PhysicalType::ByteArray, None | Some(LogicalType::String))
+        );
+        let values = match (&decl.values, &stats.distinct_texts) {
+            (Some(values), _) if is_text => values.clone(),
+            (None, Some(distinct))
+                if logical_type == Some(LogicalType::String)
+                    && dictionary
+                    && !distinct.is_empty()
+                    && stats.n_texts == n_values =>
+            {
+                distinct.iter().cloned().collect()
+            }
+            _ => vec![],
+        };
+        if !values.is_empty() {
+            return Column {
+                name,
+                physical_type: PhysicalType::ByteArray,
+                logical_type: Some(LogicalType::Enum),
+                required,
+                encoding,
+                dictionary: true,
+                query,
+                list: false,
+                map: false,
+                values,
             };
+        }
 
-            let query = format!("SELECT {} FROM {} ORDER BY rowid", name, table);
-            Ok(Column {
+        Column {
+            name,
+            physical_type,
+            logical_type,
+            required,
+            encoding,
+            dictionary,
+            query,
+            list: false,
+            map: false,
+            values: vec![],
+        }
+    }
+}
+
+impl TableScan {
+    /// If `shred_json` is set and the sampled values of the `i`th column are
+    /// all JSON objects, replaces `col` with a column for each field.
+    /// `select` makes a query from an expression over the original columns.
+    fn shred_json(
+        &self,
+        i: usize,
+        col: Column,
+        select: impl Fn(&str) -> String,
+        opts: &InferOptions,
+    ) -> Vec<Column> {
+        if !opts.shred_json
+            || !matches!(
+                col.logical_type,
+                Some(LogicalType::Json | LogicalType::String)
+            )
+        {
+            return vec![col];
+        }
+        let mut root = JsonField::default();
+        for row in &self.sample {
+            match &row[i] {
+                Value::Null => (),
+                Value::Text(x) => match serde_json::from_str(x) {
+                    Ok(x @ serde_json::Value::Object(_)) => root.add(&x),
+                    _ => return vec![col],
+                },
+                _ => return vec![col],
+            }
+        }
+        if root.fields.is_empty() {
+            return vec![col];
+        }
+        let mut cols = vec![];
+        let source = quote(&col.name);
+        match root.columns(&col.name, "$", &source, &select, opts, &mut cols) {
+            Ok(()) => {
+                info!("Shredded {} into {} columns", col.name, cols.len());
+                cols
+            }
+            Err(e) => {
+                warn!("Not shredding {}: {e}", col.name);
+                vec![col]
+            }
+        }
+    }
+}
+
+/// The values seen at some path within the JSON objects in a column
+#[derive(Default)]
+struct JsonField {
+    key: String,
+    n_bools: u64,
+    n_integers: u64,
+    n_reals: u64,
+    n_texts: u64,
+    n_arrays: u64,
+    n_objects: u64,
+    /// The non-null values which aren't objects
+    values: Vec<Value>,
+    /// The fields of the objects, in the order they were first seen
+    fields: Vec<JsonField>,
+}
+
+impl JsonField {
+    fn add(&mut self, x: &serde_json::Value) {
+        use serde_json::Value as Json;
+        match x {
+            Json::Null => return,
+            Json::Bool(_) => self.n_bools += 1,
+            Json::Number(x) if x.is_i64() => self.n_integers += 1,
+            Json::Number(_) => self.n_reals += 1,
+            Json::String(_) => self.n_texts += 1,
+            Json::Array(_) => self.n_arrays += 1,
+            Json::Object(x) => {
+                self.n_objects += 1;
+                for (key, x) in x {
+                    let idx = match self.fields.iter().position(|f| &f.key == key) {
+                        Some(idx) => idx,
+                        None => {
+                            self.fields.push(JsonField {
+                                key: key.clone(),
+                                ..JsonField::default()
+                            });
+                            self.fields.len() - 1
+                        }
+                    };
+                    self.fields[idx].add(x);
+                }
+                return;
+            }
+        }
+        self.values.push(crate::conversion::json_to_value(x));
+    }
+
+    /// Appends a column for each leaf field to `cols`.  Fields which are
+    /// always objects become groups; the others are extracted as-is.
+    fn columns(
+        &self,
+        name: &str,
+        path: &str,
+        source: &str,
+        select: &impl Fn(&str) -> String,
+        opts: &InferOptions,
+        cols: &mut Vec<Column>,
+    ) -> Result<()> {
+        for field in &self.fields {
+            let key = &field.key;
+            if key.is_empty() || key.contains(['.', '"']) {
+                anyhow::bail!("can't represent the field {key:?}");
+            }
+            let name = format!("{name}.{key}");
+            let path = format!("{path}.\"{key}\"");
+            let n_scalars =
+                field.n_bools + field.n_integers + field.n_reals + field.n_texts + field.n_arrays;
+            if n_scalars == 0 && !field.fields.is_empty() {
+                field.columns(&name, &path, source, select, opts, cols)?;
+                continue;
+            }
+            let extract = format!("json_extract({source}, '{}')", path.replace('\'', "''"));
+            let n_values = n_scalars + field.n_objects;
+            let (physical_type, logical_type, expr) = match () {
+                _ if field.n_arrays > 0 || field.n_objects > 0 => {
+                    (PhysicalType::ByteArray, Some(LogicalType::Json), extract)
+                }
+                _ if n_values > 0 && field.n_bools == n_values => {
+                    (PhysicalType::Boolean, None, extract)
+                }
+                _ if n_values > 0 && field.n_integers == n_values => {
+                    (PhysicalType::Int64, None, extract)
+                }
+                _ if n_values > 0 && field.n_integers + field.n_reals == n_values => (
+                    PhysicalType::Double,
+                    None,
+                    format!("CAST({extract} AS REAL)"),
+                ),
+                _ => (PhysicalType::ByteArray, Some(LogicalType::String), extract),
+            };
+            let dictionary = physical_type != PhysicalType::Boolean && {
+                let distinct = field
+                    .values
+                    .iter()
+                    .filter_map(ValueKey::new)
+                    .collect::<std::collections::HashSet<_>>();
+                let prop_unique = distinct.len() as f64 / field.values.len() as f64;
+                !field.values.is_empty() && prop_unique < opts.dictionary_threshold
+            };
+            cols.push(Column {
                 name,
+                required: false,
                 physical_type,
                 logical_type,
-                required,
-                encoding,
+                encoding: None,
                 dictionary,
-                query,
-            })
-        }))
+                query: select(&expr),
+                list: false,
+                map: false,
+                values: vec![],
+            });
+        }
+        Ok(())
+    }
+}
+
+impl ColumnStats {
+    fn add(&mut self, x: ValueRef) {
+        self.n_rows += 1;
+        match x {
+            ValueRef::Null => self.n_nulls += 1,
+            ValueRef::Integer(x) => {
+                self.n_integers += 1;
+                self.min_int = Some(self.min_int.map_or(x, |y| y.min(x)));
+                self.max_int = Some(self.max_int.map_or(x, |y| y.max(x)));
+            }
+            ValueRef::Real(_) => self.n_reals += 1,
+            ValueRef::Text(x) => {
+                self.n_texts += 1;
+                let text = std::str::from_utf8(x).ok();
+                if text.and_then(crate::conversion::parse_bool).is_some() {
+                    self.n_bool_texts += 1;
+                }
+                if let Some(distinct) = &mut self.distinct_texts {
+                    distinct.insert(String::from_utf8_lossy(x).into_owned());
+                    if distinct.len() > self.max_distinct {
+                        self.distinct_texts = None;
+                    }
+                }
+            }
+            ValueRef::Blob(_) => (),
+        }
+    }
+}
+
+/// A value which can be put in a `HashSet`.  `None` for nulls.
+#[derive(PartialEq, Eq, Hash)]
+enum ValueKey<'a> {
+    Integer(i64),
+    Real(u64),
+    Text(&'a str),
+    Blob(&'a [u8]),
+}
+
+impl<'a> ValueKey<'a> {
+    fn new(x: &'a Value) -> Option<ValueKey<'a>> {
+        match x {
+            Value::Null => None,
+            Value::Integer(x) => Some(ValueKey::Integer(*x)),
+            Value::Real(x) => Some(ValueKey::Real(x.to_bits())),
+            Value::Text(x) => Some(ValueKey::Text(x)),
+            Value::Blob(x) => Some(ValueKey::Blob(x)),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Clone, serde::Deserialize)]
+/// A small PRNG (splitmix64), so that we don't need a dependency for sampling
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Column {
+    /// Dotted names (eg. `a.b`) are nested inside groups.  A part ending in
+    /// `[]` (eg. `items[].qty`) makes a list of structs: the values of each
+    /// field should be JSON arrays, of the same length.
     pub name: String,
     pub required: bool,
     pub physical_type: PhysicalType,
@@ -157,9 +1115,24 @@ pub struct Column {
     pub encoding: Option<Encoding>,
     pub dictionary: bool,
     pub query: String,
+    /// Makes this a list column.  The values returned by the query should
+    /// be JSON arrays (eg. from `json_group_array()`), and `physical_type`
+    /// and `logical_type` describe their elements.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub list: bool,
+    /// Makes this a map column, with string keys.  The values returned by
+    /// the query should be JSON objects (eg. from `json_group_object()`), and
+    /// `physical_type` and `logical_type` describe the values.  See
+    /// [`infer_map_column()`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub map: bool,
+    /// For enum columns, the allowed values.  These are recorded in the
+    /// file's key-value metadata, but they aren't enforced.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>,
 }
 
-#[derive(Debug, PartialEq, Clone, Copy, serde::Deserialize)]
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum PhysicalType {
     Boolean,
     Int32,
@@ -171,7 +1144,7 @@ pub enum PhysicalType {
     FixedLenByteArray(i32),
 }
 
-#[derive(Debug, PartialEq, Clone, Copy, serde::Deserialize)]
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum Encoding {
     Plain,
     Rle,
@@ -183,7 +1156,7 @@ pub enum Encoding {
     ByteStreamSplit,
 }
 
-#[derive(Debug, PartialEq, Clone, Copy, serde::Deserialize)]
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum LogicalType {
     String,
     Map,
@@ -203,13 +1176,13 @@ pub enum LogicalType {
     // },
 }
 
-#[derive(Debug, PartialEq, Clone, Copy, serde::Deserialize)]
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub struct TimeType {
     pub utc: bool,
     pub unit: TimeUnit,
 }
 
-#[derive(Debug, PartialEq, Clone, Copy, serde::Deserialize)]
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum TimeUnit {
     Millis,
     Micros,
@@ -229,7 +1202,7 @@ impl fmt::Display for Column {
             },
             if self.dictionary { " + dict" } else { "" },
         );
-        let logical_type = match self.logical_type {
+        let mut logical_type = match self.logical_type {
             Some(x) => x.to_string(),
             None => match self.physical_type {
                 PhysicalType::Boolean => "Boolean".into(),
@@ -238,11 +1211,22 @@ impl fmt::Display for Column {
                 PhysicalType::ByteArray | PhysicalType::FixedLenByteArray(_) => "Blob".into(),
             },
         };
+        if self.list {
+            logical_type = format!("List ({logical_type})");
+        }
+        if self.map {
+            logical_type = format!("Map ({logical_type})");
+        }
         write!(
             f,
-            "{:20} {required} {physical_type:15} {encoding:20} {logical_type:26} \"{};\"",
-            self.name, self.query,
-        )
+            "{:20} {required} {physical_type:15} {encoding:20} {logical_type:26}",
+            self.name,
+        )?;
+        // Columns read back from a parquet file don't have a query
+        if !self.query.is_empty() {
+            write!(f, " \"{};\"", self.query)?;
+        }
+        Ok(())
     }
 }
 
@@ -363,7 +1347,54 @@ impl LogicalType {
     }
 }
 
+impl LogicalType {
+    fn from_parquet(x: &parquet::basic::LogicalType) -> Option<LogicalType> {
+        use parquet::basic::LogicalType as L;
+        Some(match x {
+            L::String => LogicalType::String,
+            L::Map => LogicalType::Map,
+            L::List => LogicalType::List,
+            L::Enum => LogicalType::Enum,
+            L::Date => LogicalType::Date,
+            L::Time {
+                is_adjusted_to_u_t_c,
+                unit,
+            } => LogicalType::Time(TimeType {
+                utc: *is_adjusted_to_u_t_c,
+                unit: TimeUnit::from_parquet(unit),
+            }),
+            L::Timestamp {
+                is_adjusted_to_u_t_c,
+                unit,
+            } => LogicalType::Timestamp(TimeType {
+                utc: *is_adjusted_to_u_t_c,
+                unit: TimeUnit::from_parquet(unit),
+            }),
+            L::Json => LogicalType::Json,
+            L::Bson => LogicalType::Bson,
+            L::Uuid => LogicalType::Uuid,
+            L::Unknown => LogicalType::Unknown,
+            L::Integer {
+                bit_width,
+                is_signed,
+            } => LogicalType::Integer {
+                bit_width: *bit_width,
+                is_signed: *is_signed,
+            },
+            _ => return None,
+        })
+    }
+}
+
 impl TimeUnit {
+    fn from_parquet(x: &parquet::format::TimeUnit) -> TimeUnit {
+        match x {
+            parquet::format::TimeUnit::MILLIS(_) => TimeUnit::Millis,
+            parquet::format::TimeUnit::MICROS(_) => TimeUnit::Micros,
+            parquet::format::TimeUnit::NANOS(_) => TimeUnit::Nanos,
+        }
+    }
+
     fn as_parquet(&self) -> parquet::format::TimeUnit {
         match self {
             TimeUnit::Millis => {
@@ -379,8 +1410,108 @@ impl TimeUnit {
     }
 }
 
+/// The allowed values of an enum column are recorded in the file's key-value
+/// metadata under `<ENUM_VALUES_KEY>.<column name>`, as a JSON array
+pub(crate) const ENUM_VALUES_KEY: &str = "sqlite2parquet.enum_values";
+
+/// For each leaf column in the parquet schema, the index of the column in
+/// `cols` which it belongs to
+pub(crate) fn leaf_columns(cols: &[Column]) -> Vec<usize> {
+    cols.iter()
+        .enumerate()
+        .flat_map(|(i, col)| std::iter::repeat_n(i, col.n_leaves()))
+        .collect()
+}
+
+/// Builds the top-level fields of a parquet schema.  Columns with dotted
+/// names (eg. `a.b.c`) are nested inside groups, which must be adjacent.
+pub(crate) fn parquet_fields(cols: &[Column]) -> Result<Vec<parquet::schema::types::TypePtr>> {
+    let leaves = cols
+        .iter()
+        .map(|col| Ok((col.name.split('.').collect(), Arc::new(col.as_parquet()?))))
+        .collect::<Result<Vec<_>>>()?;
+    nest_fields(&leaves, 0)
+}
+
+fn nest_fields(
+    leaves: &[(Vec<&str>, parquet::schema::types::TypePtr)],
+    depth: usize,
+) -> Result<Vec<parquet::schema::types::TypePtr>> {
+    use parquet::schema::types::Type;
+    let mut fields = vec![];
+    let mut seen = std::collections::HashSet::new();
+    let mut i = 0;
+    while i < leaves.len() {
+        let (parts, leaf) = &leaves[i];
+        let name = parts[depth];
+        if !seen.insert(name) {
+            anyhow::bail!(
+                "{}: the name {:?} is used twice, or its columns aren't adjacent",
+                parts.join("."),
+                parts[..=depth].join("."),
+            );
+        }
+        if parts.len() == depth + 1 {
+            fields.push(leaf.clone());
+            i += 1;
+            continue;
+        }
+        let n = leaves[i..]
+            .iter()
+            .take_while(|(x, _)| x.len() > depth + 1 && x[depth] == name)
+            .count();
+        let inner = nest_fields(&leaves[i..i + n], depth + 1)?;
+        let group = match name.strip_suffix("[]") {
+            None => Type::group_type_builder(name)
+                .with_repetition(parquet::basic::Repetition::REQUIRED)
+                .with_fields(inner)
+                .build()?,
+            // A list of structs, in the standard three-level structure
+            Some(name) => {
+                let element = Type::group_type_builder("element")
+                    .with_repetition(parquet::basic::Repetition::REQUIRED)
+                    .with_fields(inner)
+                    .build()?;
+                let list = Type::group_type_builder("list")
+                    .with_repetition(parquet::basic::Repetition::REPEATED)
+                    .with_fields(vec![Arc::new(element)])
+                    .build()?;
+                Type::group_type_builder(name)
+                    .with_logical_type(Some(parquet::basic::LogicalType::List))
+                    .with_repetition(parquet::basic::Repetition::OPTIONAL)
+                    .with_fields(vec![Arc::new(list)])
+                    .build()?
+            }
+        };
+        fields.push(Arc::new(group));
+        i += n;
+    }
+    Ok(fields)
+}
+
 impl Column {
+    /// The leaf of the parquet schema: for dotted names, the groups are built
+    /// by [`parquet_fields()`]
     pub(crate) fn as_parquet(&self) -> Result<parquet::schema::types::Type> {
+        use parquet::schema::types::Type;
+        if self.logical_type == Some(LogicalType::List) {
+            anyhow::bail!(
+                "{}: declare list columns with `list: true`, and give the element type",
+                self.name
+            );
+        }
+        if self.logical_type == Some(LogicalType::Map) {
+            anyhow::bail!(
+                "{}: declare map columns with `map: true`, and give the value type",
+                self.name
+            );
+        }
+        if self.list && self.map {
+            anyhow::bail!("{}: a column can't be both a list and a map", self.name);
+        }
+        if self.leaf_name().ends_with("[]") {
+            anyhow::bail!("{}: declare list columns with `list: true`", self.name);
+        }
         let repetition = match self.required {
             true => parquet::basic::Repetition::REQUIRED,
             false => parquet::basic::Repetition::OPTIONAL,
@@ -388,13 +1519,186 @@ impl Column {
         let physical_type = self.physical_type.as_parquet();
         let length = self.physical_type.len().unwrap_or(0);
         let logical_type = self.logical_type.map(|x| x.as_parquet());
-        Ok(
-            parquet::schema::types::Type::primitive_type_builder(&self.name, physical_type)
+        if self.map {
+            // The standard map structure.  Values are nullable, since the
+            // JSON may contain nulls.
+            let key = Type::primitive_type_builder("key", parquet::basic::Type::BYTE_ARRAY)
+                .with_logical_type(Some(parquet::basic::LogicalType::String))
+                .with_repetition(parquet::basic::Repetition::REQUIRED)
+                .build()?;
+            let value = Type::primitive_type_builder("value", physical_type)
                 .with_logical_type(logical_type)
-                .with_repetition(repetition)
+                .with_repetition(parquet::basic::Repetition::OPTIONAL)
                 .with_length(length)
-                .build()?,
-        )
+                .build()?;
+            let key_value = Type::group_type_builder("key_value")
+                .with_repetition(parquet::basic::Repetition::REPEATED)
+                .with_fields(vec![Arc::new(key), Arc::new(value)])
+                .build()?;
+            return Ok(Type::group_type_builder(self.leaf_name())
+                .with_logical_type(Some(parquet::basic::LogicalType::Map))
+                .with_repetition(repetition)
+                .with_fields(vec![Arc::new(key_value)])
+                .build()?);
+        }
+        if !self.list {
+            return Ok(
+                Type::primitive_type_builder(self.leaf_name(), physical_type)
+                    .with_logical_type(logical_type)
+                    .with_repetition(repetition)
+                    .with_length(length)
+                    .build()?,
+            );
+        }
+        // The standard three-level list structure.  Elements are nullable,
+        // since a JSON array may contain nulls.
+        let element = Type::primitive_type_builder("element", physical_type)
+            .with_logical_type(logical_type)
+            .with_repetition(parquet::basic::Repetition::OPTIONAL)
+            .with_length(length)
+            .build()?;
+        let list = Type::group_type_builder("list")
+            .with_repetition(parquet::basic::Repetition::REPEATED)
+            .with_fields(vec![Arc::new(element)])
+            .build()?;
+        Ok(Type::group_type_builder(self.leaf_name())
+            .with_logical_type(Some(parquet::basic::LogicalType::List))
+            .with_repetition(repetition)
+            .with_fields(vec![Arc::new(list)])
+            .build()?)
+    }
+
+    /// The number of columns this is written as in the parquet file: two
+    /// for maps (the keys and the values), otherwise one
+    pub fn n_leaves(&self) -> usize {
+        if self.map {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// The paths of the column's leaves within the parquet schema
+    pub(crate) fn paths(&self) -> Vec<parquet::schema::types::ColumnPath> {
+        let parts = self
+            .name
+            .split('.')
+            .flat_map(|x| match x.strip_suffix("[]") {
+                Some(x) => vec![x, "list", "element"],
+                None => vec![x],
+            })
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let leaves: &[&[&str]] = match () {
+            _ if self.list => &[&["list", "element"]],
+            _ if self.map => &[&["key_value", "key"], &["key_value", "value"]],
+            _ => &[&[]],
+        };
+        leaves
+            .iter()
+            .map(|leaf| {
+                let mut parts = parts.clone();
+                parts.extend(leaf.iter().map(|x| x.to_string()));
+                parquet::schema::types::ColumnPath::new(parts)
+            })
+            .collect()
+    }
+
+    /// The last part of a dotted name
+    fn leaf_name(&self) -> &str {
+        self.name.rsplit('.').next().unwrap_or(&self.name)
+    }
+
+    /// Recover a column definition from the schema of an existing parquet
+    /// file.  The encoding is taken from the given column chunk, if any.
+    /// The query is left empty.
+    ///
+    /// Types which we don't support are approximated: `INT96` becomes a
+    /// 12-byte array, and unsupported logical types are dropped.  In that
+    /// case the type from the file is returned too.
+    pub(crate) fn from_parquet(
+        descr: &parquet::schema::types::ColumnDescriptor,
+        chunk: Option<&parquet::file::metadata::ColumnChunkMetaData>,
+    ) -> (Column, Option<String>) {
+        use parquet::basic::Type as T;
+        let mut unsupported = None;
+        let physical_type = match descr.physical_type() {
+            T::BOOLEAN => PhysicalType::Boolean,
+            T::INT32 => PhysicalType::Int32,
+            T::INT64 => PhysicalType::Int64,
+            T::FLOAT => PhysicalType::Float,
+            T::DOUBLE => PhysicalType::Double,
+            T::BYTE_ARRAY => PhysicalType::ByteArray,
+            T::FIXED_LEN_BYTE_ARRAY => PhysicalType::FixedLenByteArray(descr.type_length()),
+            T::INT96 => {
+                unsupported = Some("INT96".to_string());
+                PhysicalType::FixedLenByteArray(12)
+            }
+        };
+        let logical_type = descr.logical_type().and_then(|x| {
+            let y = LogicalType::from_parquet(&x);
+            if y.is_none() {
+                unsupported = Some(format!("{} ({x:?})", descr.physical_type()));
+            }
+            y
+        });
+        // List columns are written as `<name>.list.element`, and maps as
+        // `<name>.key_value.key` and `<name>.key_value.value`.  The fields of
+        // a list of structs are written as `<name>.list.element.<field>`, and
+        // named `<name>[].<field>`.
+        let parts = descr.path().parts();
+        let ends_with =
+            |x: [&str; 2]| descr.max_rep_level() > 0 && parts.ends_with(&x.map(String::from));
+        let map = ends_with(["key_value", "value"]);
+        let list = ends_with(["list", "element"]);
+        let n_parts = parts.len() - if list || map { 2 } else { 0 };
+        let mut names = vec![];
+        let mut rest = &parts[..n_parts];
+        while let Some((first, tail)) = rest.split_first() {
+            if tail.starts_with(&["list".to_string(), "element".to_string()]) {
+                names.push(format!("{first}[]"));
+                rest = &tail[2..];
+            } else {
+                names.push(first.clone());
+                rest = tail;
+            }
+        }
+        let required = if list || map {
+            descr.max_def_level() == 2
+        } else {
+            descr.self_type().get_basic_info().repetition() == parquet::basic::Repetition::REQUIRED
+        };
+        let (encoding, dictionary) = match chunk {
+            Some(chunk) => {
+                use parquet::basic::Encoding as E;
+                let encs = chunk.encodings();
+                let dictionary = encs
+                    .iter()
+                    .any(|x| matches!(x, E::RLE_DICTIONARY | E::PLAIN_DICTIONARY));
+                let encoding = encs.iter().find_map(|x| match x {
+                    E::DELTA_BINARY_PACKED => Some(Encoding::DeltaBinaryPacked),
+                    E::DELTA_LENGTH_BYTE_ARRAY => Some(Encoding::DeltaLengthByteArray),
+                    E::DELTA_BYTE_ARRAY => Some(Encoding::DeltaByteArray),
+                    E::BYTE_STREAM_SPLIT => Some(Encoding::ByteStreamSplit),
+                    _ => None,
+                });
+                (encoding, dictionary)
+            }
+            None => (None, false),
+        };
+        let col = Column {
+            name: names.join("."),
+            required,
+            physical_type,
+            logical_type,
+            encoding,
+            dictionary,
+            query: String::new(),
+            list,
+            map,
+            values: vec![],
+        };
+        (col, unsupported)
     }
 
     pub(crate) fn encoding(&self) -> Option<parquet::basic::Encoding> {
@@ -410,3 +1714,182 @@ impl Column {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Infers the schema of the table `t`
+    fn infer(sql: &str) -> Vec<Column> {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(sql).unwrap();
+        infer_schema(&conn, "t")
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    fn integer(bit_width: i8, is_signed: bool) -> Option<LogicalType> {
+        Some(LogicalType::Integer {
+            bit_width,
+            is_signed,
+        })
+    }
+
+    #[test]
+    fn tokens() {
+        let sql = "CREATE TABLE [a b] (\"x\"\"y\" TEXT, -- it's a comment
+            `z` /* another ) */ CHECK(z IN ('it''s', 'b')))";
+        let word = |x: &str| Token::Word(x.to_string());
+        let quoted = |x: &str| Token::Quoted(x.to_string());
+        let string = |x: &str| Token::Str(x.to_string());
+        let punct = Token::Punct;
+        assert_eq!(
+            tokenize(sql),
+            [
+                word("CREATE"),
+                word("TABLE"),
+                quoted("a b"),
+                punct('('),
+                quoted("x\"y"),
+                word("TEXT"),
+                punct(','),
+                quoted("z"),
+                word("CHECK"),
+                punct('('),
+                word("z"),
+                word("IN"),
+                punct('('),
+                string("it's"),
+                punct(','),
+                string("b"),
+                punct(')'),
+                punct(')'),
+                punct(')'),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_constraints() {
+        let sql = "CREATE TABLE t (
+            a TEXT CHECK (a IN ('x', 'y')),
+            \"B\" TEXT NOT NULL check((\"B\" in ('p'))),
+            c TEXT CHECK (c IN ('x') OR c IS NULL),
+            d TEXT CHECK (d IN ('x', 1)),
+            e TEXT CHECK (length(e) IN (1, 2)),
+            f TEXT CHECK (f IN ()),
+            g TEXT, CHECK (g IN ('-- not a comment'))
+        )";
+        let checks = enum_checks(sql);
+        let mut keys = checks.keys().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, ["a", "b", "g"]);
+        assert_eq!(checks["a"], ["x", "y"]);
+        assert_eq!(checks["b"], ["p"]);
+        assert_eq!(checks["g"], ["-- not a comment"]);
+    }
+
+    #[test]
+    fn booleans() {
+        let sql = "CREATE TABLE t (a INTEGER, b TEXT, c INTEGER, d BOOL, e TEXT);
+            INSERT INTO t VALUES (0, 'yes', 0, 1, 'no');
+            INSERT INTO t VALUES (1, ' F ', 2, 0, 'maybe');
+            INSERT INTO t VALUES (NULL, 'On', 1, NULL, 'yes');";
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(sql).unwrap();
+        let types = |opts: InferOptions| {
+            infer_schema_with_options(&conn, "t", &opts)
+                .unwrap()
+                .map(|x| x.unwrap().physical_type)
+                .collect::<Vec<_>>()
+        };
+        let (bool, int, text) = (
+            PhysicalType::Boolean,
+            PhysicalType::Int32,
+            PhysicalType::ByteArray,
+        );
+        // Text is only turned into booleans when parsing leniently
+        assert_eq!(types(Default::default()), [bool, text, int, bool, text]);
+        let lenient = InferOptions {
+            lenient_bools: true,
+            ..Default::default()
+        };
+        assert_eq!(types(lenient.clone()), [bool, bool, int, bool, text]);
+
+        // A sample isn't enough, unless the column is declared BOOL
+        let sampled = InferOptions {
+            max_rows: Some(2),
+            ..lenient
+        };
+        let int = PhysicalType::Int64;
+        assert_eq!(types(sampled), [int, text, int, bool, text]);
+    }
+
+    #[test]
+    fn extreme_rowids() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE t (x INTEGER);
+            INSERT INTO t (rowid, x) VALUES (-9223372036854775808, 1), (-1, 2), (0, 3),
+                (4611686018427387904, 4), (9223372036854775807, 5);",
+        )
+        .unwrap();
+        let opts = InferOptions {
+            max_rows: Some(4),
+            ..Default::default()
+        };
+        let scan = TableScan::table(&conn, "t", &["x"], &opts).unwrap();
+        assert!(scan.sampled);
+        // The targets are spaced 2^62 apart, starting at i64::MIN
+        assert_eq!(scan.n_seen, 4);
+        assert_eq!(scan.stats[0].min_int, Some(1));
+        assert_eq!(scan.stats[0].max_int, Some(4));
+    }
+
+    #[test]
+    fn integer_widths() {
+        let cols = infer(
+            "CREATE TABLE t (a INTEGER, b INTEGER, c INTEGER, d INTEGER, e INTEGER);
+            INSERT INTO t VALUES (0, -129, 127, 2147483647, -2147483649);
+            INSERT INTO t VALUES (100, 0, -128, 32768, 0);",
+        );
+        let types = cols
+            .iter()
+            .map(|x| (x.physical_type, x.logical_type))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            [
+                (PhysicalType::Int32, integer(8, true)),
+                (PhysicalType::Int32, integer(16, true)),
+                (PhysicalType::Int32, integer(8, true)),
+                (PhysicalType::Int32, integer(32, true)),
+                (PhysicalType::Int64, integer(64, true)),
+            ]
+        );
+    }
+
+    #[test]
+    fn unsigned_integers() {
+        let cols = infer(
+            "CREATE TABLE t (a INTEGER UNSIGNED, b INT UNSIGNED, c INTEGER UNSIGNED, d INTEGER UNSIGNED);
+            INSERT INTO t VALUES (255, 65536, 2147483648, -1);",
+        );
+        let types = cols
+            .iter()
+            .map(|x| (x.physical_type, x.logical_type))
+            .collect::<Vec<_>>();
+        // Unsigned 32-bit values are stored in an i32, so one which doesn't
+        // fit in 31 bits needs an Int64.  Negative values make it signed.
+        assert_eq!(
+            types,
+            [
+                (PhysicalType::Int32, integer(8, false)),
+                (PhysicalType::Int32, integer(32, false)),
+                (PhysicalType::Int64, integer(64, false)),
+                (PhysicalType::Int32, integer(8, true)),
+            ]
+        );
+    }
+}
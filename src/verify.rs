@@ -0,0 +1,394 @@
+use crate::conversion::FromSqlite;
+use crate::read::ParquetFile;
+use crate::{quote, Column, PhysicalType, Result};
+use anyhow::{bail, Context};
+use fallible_streaming_iterator::FallibleStreamingIterator;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::Connection;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::path::Path;
+
+/// Checks that a parquet file contains the same data as the sqlite table it
+/// was exported from.
+///
+/// The columns of the file are compared against the columns of `table` with
+/// the same names, in rowid order.  See [`verify()`] for the details.
+///
+/// Boolean columns may have been parsed leniently from text like `'yes'`,
+/// or from numbers other than 0 and 1.  Those values match, since that's
+/// how they were written, but the original can't be recovered from the
+/// file, so they're counted in [`Report::lenient_values`].
+pub fn verify_table(conn: &Connection, table: &str, path: &Path) -> Result<Report> {
+    let file = ParquetFile::open(path)?;
+    let cols = file
+        .cols
+        .iter()
+        .map(|col| {
+            let query = format!(
+                "SELECT {} FROM {} ORDER BY rowid",
+                quote(&col.name),
+                quote(table)
+            );
+            Column {
+                query,
+                ..col.clone()
+            }
+        })
+        .collect::<Vec<_>>();
+    verify_file(conn, &cols, file)
+}
+
+/// Checks that a parquet file contains the data described by `cols`.
+///
+/// The queries are run in the same way as by [`write_table()`](crate::write_table()),
+/// and the results are compared with the contents of the file, after
+/// decoding them back to sqlite values.  Values from sqlite are converted
+/// to floats the same way as when they're written, so an integer in a float
+/// column matches.  Both sides are summarised (row
+/// counts, null counts, min/max, and an order-independent hash of the
+/// content), and the rows are compared one-by-one.
+pub fn verify(conn: &Connection, cols: &[Column], path: &Path) -> Result<Report> {
+    verify_file(conn, cols, ParquetFile::open(path)?)
+}
+
+fn verify_file(conn: &Connection, cols: &[Column], file: ParquetFile) -> Result<Report> {
+    if !file
+        .cols
+        .iter()
+        .map(|x| &x.name)
+        .eq(cols.iter().map(|x| &x.name))
+    {
+        bail!("The columns of the file don't match");
+    }
+    let mut stmnts = cols
+        .iter()
+        .map(|col| conn.prepare(&col.query).context(col.query.clone()))
+        .collect::<Result<Vec<_>>>()?;
+    let mut selects = stmnts
+        .iter_mut()
+        .map(|x| x.query([]))
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for s in &mut selects {
+        s.advance()?;
+    }
+
+    let names = cols.iter().map(|x| x.name.clone()).collect::<Vec<_>>();
+    let mut report = Report {
+        sqlite: Summary::new(&names),
+        parquet: Summary::new(&names),
+        mismatched_rows: vec![],
+        mismatched_values: vec![0; cols.len()],
+        lenient_values: vec![0; cols.len()],
+    };
+    let mut parquet_rows = file.rows();
+    let mut row_idx = 0;
+    loop {
+        let sqlite_row = if selects[0].get().is_some() {
+            let row = selects
+                .iter_mut()
+                .zip(cols)
+                .zip(&mut report.lenient_values)
+                .map(|((x, col), n_lenient)| {
+                    let (val, lenient) = match x.get() {
+                        Some(row) => as_written(col, row.get_ref(0)?),
+                        None => bail!("The queries returned different numbers of rows"),
+                    };
+                    *n_lenient += u64::from(lenient);
+                    x.advance()?;
+                    Ok(val)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Some(row)
+        } else {
+            None
+        };
+        let parquet_row = parquet_rows.next().transpose()?;
+        if sqlite_row.is_none() && parquet_row.is_none() {
+            break;
+        }
+        if let Some(row) = &sqlite_row {
+            report.sqlite.add(row);
+        }
+        if let Some(row) = &parquet_row {
+            report.parquet.add(row);
+        }
+        let mut row_matches = true;
+        for (i, n) in report.mismatched_values.iter_mut().enumerate() {
+            let x = sqlite_row.as_ref().map(|row| &row[i]);
+            let y = parquet_row.as_ref().map(|row| &row[i]);
+            if x != y {
+                *n += 1;
+                row_matches = false;
+            }
+        }
+        if !row_matches {
+            match report.mismatched_rows.last_mut() {
+                Some(range) if range.end == row_idx => range.end += 1,
+                _ => report.mismatched_rows.push(row_idx..row_idx + 1),
+            }
+        }
+        row_idx += 1;
+    }
+    Ok(report)
+}
+
+/// The result of comparing a parquet file against its source
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub sqlite: Summary,
+    pub parquet: Summary,
+    /// Ranges of row numbers (counting from zero) where the two sides
+    /// contain different data
+    pub mismatched_rows: Vec<Range<u64>>,
+    /// For each column, the number of rows where the two sides differ
+    pub mismatched_values: Vec<u64>,
+    /// For each column, the number of booleans which were parsed leniently
+    /// (eg. `'yes'`, or `2`).  They match, but they're not what the table
+    /// holds.
+    pub lenient_values: Vec<u64>,
+}
+
+impl Report {
+    /// True if the two sides contain exactly the same data
+    pub fn is_ok(&self) -> bool {
+        self.mismatched_rows.is_empty()
+    }
+}
+
+/// Statistics about one side of the comparison
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub n_rows: u64,
+    /// A hash of the content which doesn't depend on the order of the rows
+    pub hash: u64,
+    pub columns: Vec<ColumnSummary>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ColumnSummary {
+    pub name: String,
+    pub n_nulls: u64,
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+    /// A hash of the column's values which doesn't depend on their order
+    pub hash: u64,
+}
+
+impl Summary {
+    fn new(names: &[String]) -> Summary {
+        Summary {
+            n_rows: 0,
+            hash: 0,
+            columns: names
+                .iter()
+                .map(|name| ColumnSummary {
+                    name: name.clone(),
+                    n_nulls: 0,
+                    min: None,
+                    max: None,
+                    hash: 0,
+                })
+                .collect(),
+        }
+    }
+
+    fn add(&mut self, row: &[Value]) {
+        self.n_rows += 1;
+        let mut row_hasher = std::collections::hash_map::DefaultHasher::new();
+        for (col, x) in self.columns.iter_mut().zip(row) {
+            hash_value(x, &mut row_hasher);
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            hash_value(x, &mut hasher);
+            // Summing the hashes makes the result independent of the order
+            col.hash = col.hash.wrapping_add(hasher.finish());
+            if *x == Value::Null {
+                col.n_nulls += 1;
+                continue;
+            }
+            match &col.min {
+                Some(min) if cmp_values(x, min).is_ge() => (),
+                _ => col.min = Some(x.clone()),
+            }
+            match &col.max {
+                Some(max) if cmp_values(x, max).is_le() => (),
+                _ => col.max = Some(x.clone()),
+            }
+        }
+        self.hash = self.hash.wrapping_add(row_hasher.finish());
+    }
+}
+
+/// A value from sqlite, converted the way it would be when written to a
+/// float or boolean column.  Other values are compared as they are.  The
+/// flag is set if a boolean was parsed leniently.
+fn as_written(col: &Column, x: ValueRef) -> (Value, bool) {
+    let repeated = col.list || col.map || col.name.contains("[].");
+    let converted = match col.physical_type {
+        _ if repeated || x == ValueRef::Null => None,
+        PhysicalType::Float => f32::from_sqlite(x).ok().map(f64::from).map(Value::Real),
+        PhysicalType::Double => f64::from_sqlite(x).ok().map(Value::Real),
+        PhysicalType::Boolean => {
+            let b = match x {
+                ValueRef::Integer(0 | 1) => None,
+                ValueRef::Integer(x) => Some(x != 0),
+                ValueRef::Real(x) => Some(x != 0.),
+                ValueRef::Text(x) => std::str::from_utf8(x)
+                    .ok()
+                    .and_then(crate::conversion::parse_bool),
+                ValueRef::Null | ValueRef::Blob(_) => None,
+            };
+            return match b {
+                Some(b) => (Value::Integer(b.into()), true),
+                None => (Value::from(x), false),
+            };
+        }
+        _ => None,
+    };
+    (converted.unwrap_or_else(|| Value::from(x)), false)
+}
+
+fn hash_value(x: &Value, hasher: &mut impl Hasher) {
+    match x {
+        Value::Null => 0u8.hash(hasher),
+        Value::Integer(x) => (1u8, x).hash(hasher),
+        Value::Real(x) => (2u8, x.to_bits()).hash(hasher),
+        Value::Text(x) => (3u8, x).hash(hasher),
+        Value::Blob(x) => (4u8, x).hash(hasher),
+    }
+}
+
+/// Compares values the way sqlite does: numbers come before text, which
+/// comes before blobs
+fn cmp_values(x: &Value, y: &Value) -> Ordering {
+    fn class(x: &Value) -> u8 {
+        match x {
+            Value::Null => 0,
+            Value::Integer(_) | Value::Real(_) => 1,
+            Value::Text(_) => 2,
+            Value::Blob(_) => 3,
+        }
+    }
+    match (x, y) {
+        (Value::Integer(x), Value::Integer(y)) => x.cmp(y),
+        (Value::Integer(x), Value::Real(y)) => (*x as f64).total_cmp(y),
+        (Value::Real(x), Value::Integer(y)) => x.total_cmp(&(*y as f64)),
+        (Value::Real(x), Value::Real(y)) => x.total_cmp(y),
+        (Value::Text(x), Value::Text(y)) => x.as_bytes().cmp(y.as_bytes()),
+        (Value::Blob(x), Value::Blob(y)) => x.cmp(y),
+        _ => class(x).cmp(&class(y)),
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn fmt_value(x: &Option<Value>) -> String {
+            match x {
+                None => "-".into(),
+                Some(Value::Null) => "NULL".into(),
+                Some(Value::Integer(x)) => x.to_string(),
+                Some(Value::Real(x)) => x.to_string(),
+                Some(Value::Text(x)) => format!("{x:?}"),
+                Some(Value::Blob(x)) => format!("<{} byte blob>", x.len()),
+            }
+        }
+        fn mark(ok: bool) -> &'static str {
+            if ok {
+                "ok"
+            } else {
+                "MISMATCH"
+            }
+        }
+        let (x, y) = (&self.sqlite, &self.parquet);
+        writeln!(
+            f,
+            "Rows:    {:>20} {:>20}  {}",
+            x.n_rows,
+            y.n_rows,
+            mark(x.n_rows == y.n_rows)
+        )?;
+        writeln!(
+            f,
+            "Content: {:>20x} {:>20x}  {}",
+            x.hash,
+            y.hash,
+            mark(x.hash == y.hash)
+        )?;
+        for (((x, y), n), n_lenient) in x
+            .columns
+            .iter()
+            .zip(&y.columns)
+            .zip(&self.mismatched_values)
+            .zip(&self.lenient_values)
+        {
+            writeln!(f, "  {:20} {}", x.name, mark(*n == 0))?;
+            if *n_lenient > 0 {
+                writeln!(f, "    {n_lenient} booleans parsed leniently")?;
+            }
+            if *n == 0 {
+                continue;
+            }
+            writeln!(f, "    {n} values differ")?;
+            for (what, a, b) in [
+                ("nulls", x.n_nulls.to_string(), y.n_nulls.to_string()),
+                ("min", fmt_value(&x.min), fmt_value(&y.min)),
+                ("max", fmt_value(&x.max), fmt_value(&y.max)),
+                ("hash", format!("{:x}", x.hash), format!("{:x}", y.hash)),
+            ] {
+                if a != b {
+                    writeln!(f, "    {what:5} sqlite: {a}, parquet: {b}")?;
+                }
+            }
+        }
+        if !self.mismatched_rows.is_empty() {
+            writeln!(f, "Mismatched rows:")?;
+            for range in self.mismatched_rows.iter().take(20) {
+                writeln!(f, "  {}..{}", range.start, range.end)?;
+            }
+            if self.mismatched_rows.len() > 20 {
+                writeln!(f, "  ...and {} more", self.mismatched_rows.len() - 20)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lenient_booleans() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("flags.parquet");
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE flags (a TEXT, b BOOL);
+            INSERT INTO flags VALUES ('yes', 1), ('No', 0), (NULL, 1), ('1', NULL);",
+        )
+        .unwrap();
+        let opts = crate::InferOptions {
+            lenient_bools: true,
+            ..Default::default()
+        };
+        let cols = crate::infer_schema_with_options(&conn, "flags", &opts)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let out = std::fs::File::create(&path).unwrap();
+        crate::write_table(&conn, "flags", &cols, &out, 2).unwrap();
+
+        let report = verify_table(&conn, "flags", &path).unwrap();
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(report.lenient_values, [3, 0]);
+        assert!(report.to_string().contains("3 booleans parsed leniently"));
+
+        // Values which weren't parsed leniently don't match
+        conn.execute("UPDATE flags SET a = 'maybe' WHERE a = 'yes'", [])
+            .unwrap();
+        let report = verify_table(&conn, "flags", &path).unwrap();
+        assert_eq!(report.mismatched_values, [1, 0]);
+    }
+}
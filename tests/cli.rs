@@ -0,0 +1,244 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Runs sqlite2parquet, checks that it succeeded, and returns its stdout
+fn run(args: &[&str]) -> String {
+    let out = Command::new(env!("CARGO_BIN_EXE_sqlite2parquet"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        out.status.success(),
+        "sqlite2parquet {args:?} failed:\n{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    String::from_utf8(out.stdout).unwrap()
+}
+
+fn mk_db(dir: &Path, sql: &str) -> String {
+    let path = dir.join("db.sqlite");
+    rusqlite::Connection::open(&path)
+        .unwrap()
+        .execute_batch(sql)
+        .unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn resume_notices_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = mk_db(
+        dir.path(),
+        "CREATE TABLE a (x INTEGER); INSERT INTO a VALUES (1), (2);",
+    );
+    let out_dir = dir.path().join("out");
+    let out_dir = out_dir.to_str().unwrap();
+    let stdout = run(&["export", &db, out_dir, "--resume"]);
+    assert!(stdout.contains("Exporting a"), "{stdout}");
+
+    // The row count and the largest rowid stay the same
+    let conn = rusqlite::Connection::open(&db).unwrap();
+    conn.execute("UPDATE a SET x = 3 WHERE x = 2", []).unwrap();
+    let stdout = run(&["export", &db, out_dir, "--resume"]);
+    assert!(stdout.contains("Exporting a"), "{stdout}");
+
+    conn.execute_batch("DELETE FROM a WHERE rowid = 1; INSERT INTO a (rowid, x) VALUES (1, 4);")
+        .unwrap();
+    let stdout = run(&["export", &db, out_dir, "--resume"]);
+    assert!(stdout.contains("Exporting a"), "{stdout}");
+
+    let stdout = run(&["export", &db, out_dir, "--resume"]);
+    assert!(stdout.contains("Skipping a"), "{stdout}");
+}
+
+/// The key-value metadata of a parquet file
+fn key_value_metadata(path: &Path) -> Vec<(String, Option<String>)> {
+    use parquet::file::reader::FileReader;
+    let file = std::fs::File::open(path).unwrap();
+    let rdr = parquet::file::reader::SerializedFileReader::new(file).unwrap();
+    let meta = rdr.metadata().file_metadata();
+    meta.key_value_metadata()
+        .into_iter()
+        .flatten()
+        .map(|x| (x.key.clone(), x.value.clone()))
+        .collect()
+}
+
+#[test]
+fn sha3sum_only_for_plain_tables() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = mk_db(
+        dir.path(),
+        "CREATE TABLE events (category TEXT, timestamp INTEGER);
+        INSERT INTO events VALUES ('a', 1), ('b', 2), ('a', 3);",
+    );
+    let config = dir.path().join("config.yaml");
+    std::fs::write(
+        &config,
+        r#"
+category_start_times:
+  - name: category
+    required: true
+    physical_type: ByteArray
+    logical_type: String
+    encoding: null
+    dictionary: false
+    query: SELECT category FROM events GROUP BY category ORDER BY MIN(timestamp)
+  - name: first_timestamp
+    required: true
+    physical_type: Int64
+    logical_type: null
+    encoding: null
+    dictionary: false
+    query: SELECT MIN(timestamp) FROM events GROUP BY category ORDER BY MIN(timestamp)
+"#,
+    )
+    .unwrap();
+    let out_dir = dir.path().join("out");
+    let config = config.to_str().unwrap();
+    run(&["export", &db, out_dir.to_str().unwrap(), "--config", config]);
+    let kv = key_value_metadata(&out_dir.join("category_start_times.parquet"));
+    assert!(
+        kv.iter().all(|(k, _)| k != sqlite2parquet::SHA3SUM_KEY),
+        "{kv:?}"
+    );
+
+    run(&["export", &db, out_dir.to_str().unwrap()]);
+    let path = out_dir.join("events.parquet");
+    let kv = key_value_metadata(&path);
+    let conn = rusqlite::Connection::open(&db).unwrap();
+    let expected = sqlite2parquet::sha3sum_table(&conn, "events").unwrap();
+    assert!(kv.contains(&(
+        sqlite2parquet::SHA3SUM_KEY.to_string(),
+        Some(expected.clone())
+    )));
+    assert_eq!(sqlite2parquet::sha3sum_file(&path).unwrap(), expected);
+}
+
+#[test]
+fn sha3sum_only_without_lenient_bools() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = mk_db(
+        dir.path(),
+        "CREATE TABLE t (id INTEGER PRIMARY KEY, flag TEXT, b INTEGER);
+        INSERT INTO t VALUES (1, 'yes', 1), (2, 'no', 0), (3, 'Y', 1), (4, 'off', 0);",
+    );
+    let conn = rusqlite::Connection::open(&db).unwrap();
+    let expected = sqlite2parquet::sha3sum_table(&conn, "t").unwrap();
+    let out_dir = dir.path().join("out");
+    let path = out_dir.join("t.parquet");
+
+    // By default the text is kept as it is, so the file has the same content
+    // as the table
+    run(&["export", &db, out_dir.to_str().unwrap()]);
+    let kv = key_value_metadata(&path);
+    assert!(kv.contains(&(
+        sqlite2parquet::SHA3SUM_KEY.to_string(),
+        Some(expected.clone())
+    )));
+    assert_eq!(sqlite2parquet::sha3sum_file(&path).unwrap(), expected);
+
+    // Parsing the text leniently loses the spellings
+    run(&["export", &db, out_dir.to_str().unwrap(), "--lenient-bools"]);
+    let kv = key_value_metadata(&path);
+    assert!(
+        kv.iter().all(|(k, _)| k != sqlite2parquet::SHA3SUM_KEY),
+        "{kv:?}"
+    );
+    assert_ne!(sqlite2parquet::sha3sum_file(&path).unwrap(), expected);
+    let out = run(&["verify", &db, out_dir.to_str().unwrap()]);
+    assert!(out.contains("4 booleans parsed leniently"), "{out}");
+}
+
+#[test]
+fn config_tables() {
+    use parquet::record::RowAccessor;
+    let dir = tempfile::tempdir().unwrap();
+    let db = mk_db(
+        dir.path(),
+        "CREATE TABLE include (x INTEGER); INSERT INTO include VALUES (1), (2);
+        CREATE TABLE other (y INTEGER); INSERT INTO other VALUES (3);",
+    );
+    let config = dir.path().join("config.yaml");
+    let col = |table: &str, col: &str| {
+        format!(
+            "
+  - name: {col}
+    required: true
+    physical_type: Int32
+    logical_type: null
+    encoding: null
+    dictionary: false
+    query: SELECT {col} + 10 FROM {table}"
+        )
+    };
+    // Tables can be configured under `tables:`, even if they have the same
+    // names as other keys, or at the top level as before
+    let yaml = format!(
+        "tables:\n  include:{}\nother:{}\n",
+        col("include", "x"),
+        col("other", "y")
+    );
+    std::fs::write(&config, yaml).unwrap();
+    let out_dir = dir.path().join("out");
+    let config = config.to_str().unwrap();
+    run(&["export", &db, out_dir.to_str().unwrap(), "--config", config]);
+    for (table, expected) in [("include", [11, 12].as_slice()), ("other", &[13])] {
+        let path = out_dir.join(format!("{table}.parquet"));
+        let file =
+            parquet::file::reader::SerializedFileReader::new(std::fs::File::open(path).unwrap())
+                .unwrap();
+        let xs = file
+            .into_iter()
+            .map(|row| row.unwrap().get_int(0).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(xs, expected);
+    }
+}
+
+#[test]
+fn resume_twice() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = mk_db(
+        dir.path(),
+        "CREATE TABLE a (x INTEGER); INSERT INTO a VALUES (1), (2);
+        CREATE TABLE b (x INTEGER); INSERT INTO b VALUES (1), (2);",
+    );
+    let out_dir = dir.path().join("out");
+    let out_dir = out_dir.to_str().unwrap();
+    run(&["export", &db, out_dir, "--resume"]);
+    let stdout = run(&["export", &db, out_dir, "--resume"]);
+    assert!(stdout.contains("Skipping a"), "{stdout}");
+    assert!(stdout.contains("Skipping b"), "{stdout}");
+    assert!(stdout.contains("Exported 0 rows from 2 tables"), "{stdout}");
+}
+
+#[test]
+fn reproducible() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut sql = "CREATE TABLE t (x TEXT, y INTEGER);".to_string();
+    for i in 0..200 {
+        sql += &format!("INSERT INTO t VALUES ('{}', {i});", ["b", "a", "c"][i % 3]);
+    }
+    let db = mk_db(dir.path(), &sql);
+    let export = |name: &str| {
+        let out_dir = dir.path().join(name);
+        run(&[
+            "export",
+            &db,
+            out_dir.to_str().unwrap(),
+            "--reproducible",
+            "--sample-size",
+            "10",
+        ]);
+        std::fs::read(out_dir.join("t.parquet")).unwrap()
+    };
+    let first = export("first");
+    assert_eq!(export("second"), first);
+    // A covering index could change the order of a plain scan of the table
+    rusqlite::Connection::open(&db)
+        .unwrap()
+        .execute_batch("CREATE INDEX t_x ON t (x, y); ANALYZE;")
+        .unwrap();
+    assert_eq!(export("third"), first);
+}